@@ -1,6 +1,32 @@
 use std::{
-    io::{self, IsTerminal, Write},
-    process::{Command, Output, Stdio},
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, PipeReader, PipeWriter, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    thread,
+};
+
+// Job control needs process groups and signals,
+// so unlike the earlier blocks, this shell only runs on Unix.
+use std::os::{
+    fd::AsRawFd,
+    unix::process::{CommandExt, ExitStatusExt},
+};
+
+use rustyline::{
+    config::Configurer, error::ReadlineError, history::DefaultHistory, CompletionType, Config,
+    EditMode, Editor,
+};
+
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{kill, killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{dup2, fork, getpgrp, setpgid, tcgetpgrp, tcsetpgrp, ForkResult, Pid},
 };
 
 /// Alias for our `Result` type. You could also use `anyhow` instead.
@@ -11,45 +37,412 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 /// move this module to its own file, but we keep it here to have
 /// everything in one file for learning purposes.
 mod builtins {
-    use crate::Result;
+    use crate::{JobState, Options, Result, Shell};
+    use nix::sys::signal::Signal;
     use std::io::Write;
-    use std::{path::PathBuf, process::Output};
+    use std::{
+        path::{Component, Path, PathBuf},
+        process::Output,
+    };
+
+    /// The names of the builtins, as `Cmd::run_binary` runs them.
+    /// Tab completion offers them along with the commands in `PATH`.
+    pub const NAMES: &[&str] = &[
+        "bg", "cd", "dirs", "exit", "export", "fg", "history", "jobs", "let", "popd", "pushd",
+        "readonly", "set", "shopt", "unset",
+    ];
 
     /// The `cd` command changes the current directory.
     ///
     /// The `cd` command changes the current directory of the shell.
     /// If the directory is not found, it prints an error message.
-    /// If the directory is successfully changed, it returns `Ok(())` and
-    /// the shell should update its current directory.
+    /// If the directory is successfully changed, it updates `PWD` and
+    /// `OLDPWD`, so that `$PWD` and `~-` refer to the new and the previous directory.
+    ///
+    /// Without an argument, `cd` goes to the home directory, and `cd -` goes
+    /// back to the previous directory. `cd ~/src` works too, because the
+    /// shell expands `~` before running `cd`.
     ///
-    /// A real `cd` accepts options like `-L` and `-P`, to resolve symbolic links.
-    /// It also has special cases like `cd -` to go to the previous directory or `cd ~` to go to the home directory.
-    /// We don't implement these features in this workshop, but you can give it a try!
+    /// A relative name like `cd src` that doesn't start with `.` or `..`
+    /// is also looked up in the directories of `CDPATH`, like `CDPATH=.:~/work`.
+    ///
+    /// By default, or with `-L`, `cd` works on the logical path: `cd ..` goes
+    /// back to where we came from, even if we followed a symbolic link to get here.
+    /// With `-P`, symbolic links are resolved, and `PWD` becomes the physical path.
     pub struct Cd {
-        /// The directory to change into.
-        dir: PathBuf,
+        /// The options and the directory to change into.
+        args: Vec<String>,
     }
 
     impl Cd {
         /// Create a new `Cd` command.
-        pub fn new(dir: PathBuf) -> Self {
-            Self { dir }
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
         }
 
         /// Run the `cd` command.
-        pub fn run(self) -> Result<Option<Output>> {
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let mut physical = false;
+            let mut operands = &self.args[..];
+            while let Some((arg, rest)) = operands.split_first() {
+                match arg.as_str() {
+                    "-L" => physical = false,
+                    "-P" => physical = true,
+                    "--" => {
+                        operands = rest;
+                        break;
+                    }
+                    _ => break,
+                }
+                operands = rest;
+            }
+            // `cd -` prints the directory it changes to, so we know where we are.
+            let mut print_dir = false;
+            let dir = match operands {
+                [] => shell.var("HOME").ok_or("cd: HOME not set")?.to_string(),
+                [dir] if dir == "-" => {
+                    print_dir = true;
+                    shell.var("OLDPWD").ok_or("cd: OLDPWD not set")?.to_string()
+                }
+                [dir] => dir.clone(),
+                _ => return Err("cd: too many arguments".into()),
+            };
+            let (new_pwd, found_in_cdpath) =
+                Self::change_dir(shell, &dir, physical).map_err(|e| format!("cd: {e}"))?;
+            if !print_dir && !found_in_cdpath {
+                // Otherwise, the `cd` command doesn't produce any output.
+                return Ok(None);
+            }
+            print(format!("{new_pwd}\n"))
+        }
+
+        /// Change into `dir` and update `PWD` and `OLDPWD`.
+        ///
+        /// Returns the new directory, and whether it was found in `CDPATH`.
+        /// This is shared with `pushd` and `popd`, which also change directories.
+        pub fn change_dir(shell: &mut Shell, dir: &str, physical: bool) -> Result<(String, bool)> {
+            let (dir, found_in_cdpath) = Self::search_cdpath(dir, shell.var("CDPATH"));
+            let old_pwd = match shell.var("PWD") {
+                Some(pwd) => PathBuf::from(pwd),
+                None => std::env::current_dir()?,
+            };
+            let error = |e: std::io::Error| format!("{}: {e}", dir.display());
             // `std::env::set_current_dir` changes the current directory of the process
             // (our shell in this case).
-            std::env::set_current_dir(&self.dir)?;
-            // The `cd` command doesn't produce any output.
-            Ok(None)
+            let new_pwd = if physical {
+                std::env::set_current_dir(&dir).map_err(error)?;
+                std::env::current_dir()?
+            } else {
+                let path = Self::normalize(&old_pwd.join(&dir));
+                std::env::set_current_dir(&path).map_err(error)?;
+                path
+            };
+
+            let old_pwd = old_pwd.to_string_lossy().into_owned();
+            let new_pwd = new_pwd.to_string_lossy().into_owned();
+            // Export both, so that commands we run see the new values.
+            shell.set_var("OLDPWD", old_pwd);
+            shell.set_var("PWD", new_pwd.clone());
+            shell.exported.insert("OLDPWD".to_string());
+            shell.exported.insert("PWD".to_string());
+            Ok((new_pwd, found_in_cdpath))
+        }
+
+        /// Look up `dir` in the directories of `cdpath`, if `dir` is relative
+        /// and doesn't start with `.` or `..`.
+        ///
+        /// Returns the directory to change into, and whether it was found in a
+        /// non-empty entry of `cdpath`. An empty entry stands for the current directory.
+        pub fn search_cdpath(dir: &str, cdpath: Option<&str>) -> (PathBuf, bool) {
+            let path = Path::new(dir);
+            let starts_with_dot = matches!(
+                path.components().next(),
+                Some(Component::CurDir | Component::ParentDir)
+            );
+            if let Some(cdpath) = cdpath.filter(|_| path.is_relative() && !starts_with_dot) {
+                for entry in cdpath.split(':') {
+                    let candidate = Path::new(entry).join(path);
+                    if candidate.is_dir() {
+                        return (candidate, !entry.is_empty());
+                    }
+                }
+            }
+            (path.to_path_buf(), false)
+        }
+
+        /// Remove the `.` and `..` components of an absolute path, without
+        /// looking at the file system: `/a/link/..` becomes `/a`, even if
+        /// `link` is a symbolic link to another directory.
+        pub fn normalize(path: &Path) -> PathBuf {
+            let mut normalized = PathBuf::new();
+            for component in path.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        normalized.pop();
+                    }
+                    component => normalized.push(component),
+                }
+            }
+            normalized
+        }
+    }
+
+    /// The `dirs` command prints the directory stack.
+    ///
+    /// The directory stack is a list of directories that `pushd` and `popd`
+    /// manage. The current directory is always on top of the stack, at index 0.
+    /// `dirs` prints the stack on one line, `dirs -p` prints one directory
+    /// per line and `dirs -v` numbers them. The home directory is shown
+    /// as `~`, unless `-l` is given. `dirs +N` prints the Nth directory
+    /// counting from the top, `dirs -N` counting from the bottom,
+    /// and `dirs -c` clears the stack.
+    pub struct Dirs {
+        args: Vec<String>,
+    }
+
+    impl Dirs {
+        /// Create a new `Dirs` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `dirs` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let (mut long, mut per_line, mut numbered) = (false, false, false);
+            let mut index = None;
+            for arg in &self.args {
+                match arg.as_str() {
+                    "-c" => {
+                        shell.dir_stack.clear();
+                        return Ok(None);
+                    }
+                    "-l" => long = true,
+                    "-p" => per_line = true,
+                    "-v" => (per_line, numbered) = (true, true),
+                    _ => index = Some(stack_index("dirs", arg, &stack(shell))?),
+                }
+            }
+            let stack: Vec<String> = stack(shell)
+                .into_iter()
+                .map(|dir| if long { dir } else { abbreviate(shell, dir) })
+                .collect();
+            if let Some(index) = index {
+                return print(format!("{}\n", stack[index]));
+            }
+            print_stack(&stack, per_line, numbered)
+        }
+    }
+
+    /// The `pushd` command changes the current directory and pushes it
+    /// onto the directory stack, so that `popd` can go back.
+    ///
+    /// `pushd dir` works like `cd dir`, but keeps the previous directory
+    /// on the stack. Without an argument, `pushd` swaps the two directories
+    /// on top of the stack. `pushd +N` rotates the stack, so that the Nth
+    /// directory is on top, and changes into it. With `-n`, `pushd dir`
+    /// only adds `dir` to the stack, without changing the current directory.
+    /// Afterwards, `pushd` prints the stack like `dirs`.
+    pub struct Pushd {
+        args: Vec<String>,
+    }
+
+    impl Pushd {
+        /// Create a new `Pushd` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `pushd` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let (no_cd, args) = match self.args.split_first() {
+                Some((flag, rest)) if flag == "-n" => (true, rest),
+                _ => (false, &self.args[..]),
+            };
+            let mut stack = stack(shell);
+            match args {
+                [] if stack.len() < 2 => return Err("pushd: no other directory".into()),
+                [] => stack.swap(0, 1),
+                [arg] if arg.starts_with(['+', '-']) && arg.len() > 1 => {
+                    let index = stack_index("pushd", arg, &stack)?;
+                    stack.rotate_left(index);
+                }
+                [dir] if no_cd => {
+                    stack.insert(1, dir.clone());
+                }
+                [dir] => {
+                    let (dir, _) =
+                        Cd::change_dir(shell, dir, false).map_err(|e| format!("pushd: {e}"))?;
+                    stack.insert(0, dir);
+                }
+                _ => return Err("pushd: too many arguments".into()),
+            }
+            if stack[0] != stack_top(shell) {
+                let (dir, _) =
+                    Cd::change_dir(shell, &stack[0], false).map_err(|e| format!("pushd: {e}"))?;
+                stack[0] = dir;
+            }
+            shell.dir_stack = stack.split_off(1);
+            Dirs::new(vec![]).run(shell)
+        }
+    }
+
+    /// The `popd` command removes the top directory from the directory stack
+    /// and changes into the new top directory, to go back after `pushd`.
+    ///
+    /// `popd +N` removes the Nth directory counting from the top instead,
+    /// and `popd -N` counting from the bottom. With `-n`, `popd` only changes
+    /// the stack, without changing the current directory.
+    /// Afterwards, `popd` prints the stack like `dirs`.
+    pub struct Popd {
+        args: Vec<String>,
+    }
+
+    impl Popd {
+        /// Create a new `Popd` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `popd` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let (no_cd, args) = match self.args.split_first() {
+                Some((flag, rest)) if flag == "-n" => (true, rest),
+                _ => (false, &self.args[..]),
+            };
+            let mut stack = stack(shell);
+            if stack.len() < 2 {
+                return Err("popd: directory stack empty".into());
+            }
+            let index = match args {
+                [] => 0,
+                [arg] => stack_index("popd", arg, &stack)?,
+                _ => return Err("popd: too many arguments".into()),
+            };
+            // With `-n`, the current directory stays on top.
+            let index = if no_cd && index == 0 { 1 } else { index };
+            stack.remove(index);
+            if index == 0 {
+                let (dir, _) =
+                    Cd::change_dir(shell, &stack[0], false).map_err(|e| format!("popd: {e}"))?;
+                stack[0] = dir;
+            }
+            shell.dir_stack = stack.split_off(1);
+            Dirs::new(vec![]).run(shell)
+        }
+    }
+
+    /// The directory stack, with the current directory on top.
+    fn stack(shell: &Shell) -> Vec<String> {
+        let mut stack = vec![stack_top(shell)];
+        stack.extend(shell.dir_stack.iter().cloned());
+        stack
+    }
+
+    /// The current directory, which is the top of the directory stack.
+    fn stack_top(shell: &Shell) -> String {
+        match shell.var("PWD") {
+            Some(pwd) => pwd.to_string(),
+            None => std::env::current_dir()
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Parse an index into the directory stack like `+1` or `-0`.
+    /// `+N` counts from the top of the stack, `-N` from the bottom.
+    fn stack_index(builtin: &str, arg: &str, stack: &[String]) -> Result<usize> {
+        let invalid = || format!("{builtin}: {arg}: invalid argument");
+        let mut chars = arg.chars();
+        let sign = chars.next();
+        let n: usize = chars.as_str().parse().map_err(|_| invalid())?;
+        let index = match sign {
+            Some('+') => Some(n),
+            Some('-') => (stack.len() - 1).checked_sub(n),
+            _ => return Err(invalid().into()),
+        };
+        match index {
+            Some(index) if index < stack.len() => Ok(index),
+            _ => Err(format!("{builtin}: {arg}: directory stack index out of range").into()),
+        }
+    }
+
+    /// Show the home directory as `~`, like in `~/src`.
+    fn abbreviate(shell: &Shell, dir: String) -> String {
+        match shell.var("HOME") {
+            Some(home) if !home.is_empty() && dir.starts_with(home) => match &dir[home.len()..] {
+                rest if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+                _ => dir,
+            },
+            _ => dir,
+        }
+    }
+
+    /// Print the directories of the stack, like `dirs` does.
+    fn print_stack(stack: &[String], per_line: bool, numbered: bool) -> Result<Option<Output>> {
+        let stdout = if numbered {
+            let lines = stack.iter().enumerate();
+            lines.map(|(i, dir)| format!("{i:2}  {dir}\n")).collect()
+        } else if per_line {
+            stack.iter().map(|dir| format!("{dir}\n")).collect()
+        } else {
+            format!("{}\n", stack.join(" "))
+        };
+        print(stdout)
+    }
+
+    /// The `let` command evaluates arithmetic expressions, like `let i++`.
+    ///
+    /// Its exit status is 0 if the last expression is not zero, and 1 otherwise,
+    /// so it can be used as a condition: `let "i < 10" && echo less`.
+    /// The arithmetic command `(( i < 10 ))` is a shorthand for `let "i < 10"`.
+    pub struct Let {
+        args: Vec<String>,
+    }
+
+    impl Let {
+        /// Create a new `Let` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `let` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            if self.args.is_empty() {
+                return Err("let: expression expected".into());
+            }
+            let mut value = 0;
+            for arg in &self.args {
+                value = crate::arith::eval(arg, shell).map_err(|e| format!("let: {e}"))?;
+            }
+            status(if value != 0 { 0 } else { 1 })
         }
     }
 
+    /// The output of a builtin that succeeded and printed `stdout`.
+    fn print(stdout: String) -> Result<Option<Output>> {
+        Ok(Some(Output {
+            status: std::process::ExitStatus::from_raw(0),
+            stdout: stdout.into_bytes(),
+            stderr: Vec::new(),
+        }))
+    }
+
+    /// The output of a builtin that printed nothing and exited with `code`.
+    fn status(code: i32) -> Result<Option<Output>> {
+        Ok(Some(Output {
+            // The exit code is stored in the second byte of the raw wait status.
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }))
+    }
+
     /// The `exit` command exits the shell.
     ///
     /// The `exit` command exits the shell with the given status code.
     /// If no status code is given, it exits with status code 0.
+    /// Inside a command substitution, it only ends the substitution.
     pub struct Exit {
         /// The status code to exit with.
         status: i32,
@@ -62,17 +455,390 @@ mod builtins {
         }
 
         /// Run the `exit` command.
-        pub fn run(self) -> Result<Option<Output>> {
-            // The `exit` command doesn't produce any output.
-            std::process::exit(self.status);
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            if !shell.in_substitution {
+                // The `exit` command doesn't produce any output.
+                std::process::exit(self.status);
+            }
+            shell.exit_status = Some(self.status);
+            status(self.status)
         }
     }
 
-    #[cfg(unix)]
-    use std::os::unix::process::ExitStatusExt;
+    /// The `shopt` command shows and changes the options of the shell.
+    ///
+    /// `shopt -s nullglob` sets an option and `shopt -u nullglob` unsets it.
+    /// Without `-s` or `-u`, it prints whether the given options are set,
+    /// or all options if no option is given.
+    pub struct Shopt {
+        args: Vec<String>,
+    }
+
+    impl Shopt {
+        /// Create a new `Shopt` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `shopt` command.
+        pub fn run(self, options: &mut Options) -> Result<Option<Output>> {
+            let (value, names) = match self.args.split_first() {
+                Some((flag, names)) if flag == "-s" => (Some(true), names),
+                Some((flag, names)) if flag == "-u" => (Some(false), names),
+                _ => (None, &self.args[..]),
+            };
+            let mut stdout = String::new();
+            let mut show = |name: &str, set: bool| {
+                stdout.push_str(&format!("{name}\t{}\n", if set { "on" } else { "off" }));
+            };
+            if names.is_empty() {
+                // `shopt -s` without names prints the options that are set.
+                for (name, set) in options.all() {
+                    if value.is_none_or(|value| value == set) {
+                        show(name, set);
+                    }
+                }
+            }
+            for name in names {
+                let Some(option) = options.get_mut(name) else {
+                    return Err(format!("shopt: {name}: invalid shell option name").into());
+                };
+                match value {
+                    Some(value) => *option = value,
+                    None => show(name, *option),
+                }
+            }
+            print(stdout)
+        }
+    }
+
+    /// The `export` command passes variables to the commands that the shell runs.
+    ///
+    /// `export NAME=value` assigns and exports a variable, `export NAME` exports
+    /// an existing one, and `export -n NAME` stops exporting it but keeps
+    /// it as a shell variable. Without names, or with `-p`, it prints the
+    /// exported variables in a form that can be read back by the shell.
+    pub struct Export {
+        args: Vec<String>,
+    }
+
+    impl Export {
+        /// Create a new `Export` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `export` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let (export, args) = match self.args.split_first() {
+                Some((flag, args)) if flag == "-n" => (false, args),
+                Some((flag, args)) if flag == "-p" => (true, args),
+                _ => (true, &self.args[..]),
+            };
+            if args.is_empty() {
+                let mut names: Vec<&String> = shell.exported.iter().collect();
+                names.sort();
+                let mut stdout = String::new();
+                for name in names {
+                    match shell.var(name) {
+                        Some(value) => {
+                            stdout.push_str(&format!("export {name}={}\n", quote(value)))
+                        }
+                        None => stdout.push_str(&format!("export {name}\n")),
+                    }
+                }
+                return print(stdout);
+            }
+            for arg in args {
+                let name = assign("export", shell, arg)?;
+                if export {
+                    shell.exported.insert(name);
+                } else {
+                    shell.exported.remove(&name);
+                }
+            }
+            Ok(None)
+        }
+    }
+
+    /// The `readonly` command marks variables that can't be changed or unset.
+    ///
+    /// Like `export`, it accepts `NAME=value` to assign the variable first,
+    /// and prints the readonly variables without names or with `-p`.
+    pub struct Readonly {
+        args: Vec<String>,
+    }
+
+    impl Readonly {
+        /// Create a new `Readonly` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `readonly` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let args = match self.args.split_first() {
+                Some((flag, args)) if flag == "-p" => args,
+                _ => &self.args[..],
+            };
+            if args.is_empty() {
+                let mut names: Vec<&String> = shell.readonly.iter().collect();
+                names.sort();
+                let mut stdout = String::new();
+                for name in names {
+                    match shell.var(name) {
+                        Some(value) => {
+                            stdout.push_str(&format!("readonly {name}={}\n", quote(value)))
+                        }
+                        None => stdout.push_str(&format!("readonly {name}\n")),
+                    }
+                }
+                return print(stdout);
+            }
+            for arg in args {
+                let name = assign("readonly", shell, arg)?;
+                shell.readonly.insert(name);
+            }
+            Ok(None)
+        }
+    }
+
+    /// The `unset` command removes variables.
+    ///
+    /// `unset NAME` and `unset -v NAME` remove the variable and stop exporting it.
+    /// `unset -f NAME` removes a function, but since our shell doesn't have
+    /// functions yet, there is nothing to remove. Readonly variables can't be unset.
+    pub struct Unset {
+        args: Vec<String>,
+    }
+
+    impl Unset {
+        /// Create a new `Unset` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `unset` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let (functions, names) = match self.args.split_first() {
+                Some((flag, names)) if flag == "-f" => (true, names),
+                Some((flag, names)) if flag == "-v" => (false, names),
+                _ => (false, &self.args[..]),
+            };
+            if functions {
+                return Ok(None);
+            }
+            for name in names {
+                if !crate::is_valid_name(name) {
+                    return Err(format!("unset: `{name}': not a valid identifier").into());
+                }
+                if shell.readonly.contains(name) {
+                    return Err(format!("unset: {name}: cannot unset: readonly variable").into());
+                }
+                shell.vars.remove(name);
+                shell.exported.remove(name);
+            }
+            Ok(None)
+        }
+    }
+
+    /// The `set` command prints all shell variables, or sets the positional parameters.
+    ///
+    /// Without arguments, `set` prints every variable as `NAME='value'`.
+    /// `set -- a b c` (or `set a b c`) replaces `$1`, `$2`, and so on,
+    /// and `set --` alone clears them.
+    ///
+    /// `set -o ignoreeof` sets an option and `set +o ignoreeof` unsets it.
+    /// Our shell keeps these options in the same table as `shopt`.
+    pub struct Set {
+        args: Vec<String>,
+    }
+
+    impl Set {
+        /// Create a new `Set` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `set` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            match self.args.split_first() {
+                None => {
+                    let mut vars: Vec<(&String, &String)> = shell.vars.iter().collect();
+                    vars.sort();
+                    let mut stdout = String::new();
+                    for (name, value) in vars {
+                        stdout.push_str(&format!("{name}={}\n", quote(value)));
+                    }
+                    print(stdout)
+                }
+                Some((first, args)) if first == "--" => {
+                    shell.positional = args.to_vec();
+                    Ok(None)
+                }
+                Some((first, names)) if first == "-o" || first == "+o" => {
+                    if names.is_empty() {
+                        let mut stdout = String::new();
+                        for (name, set) in shell.options.all() {
+                            let value = if set { "on" } else { "off" };
+                            stdout.push_str(&format!("{name:<15}\t{value}\n"));
+                        }
+                        return print(stdout);
+                    }
+                    for name in names {
+                        let Some(option) = shell.options.get_mut(name) else {
+                            return Err(format!("set: {name}: invalid option name").into());
+                        };
+                        *option = first == "-o";
+                        // `vi` and `emacs` choose the key bindings of the line editor,
+                        // so turning one of them on turns the other one off.
+                        match name.as_str() {
+                            "vi" => shell.options.emacs = first != "-o",
+                            "emacs" => shell.options.vi = first != "-o",
+                            _ => {}
+                        }
+                    }
+                    Ok(None)
+                }
+                Some((first, _)) if first.starts_with(['-', '+']) => {
+                    Err(format!("set: {first}: invalid option").into())
+                }
+                Some(_) => {
+                    shell.positional = self.args;
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// The `jobs` command lists the background jobs and their states.
+    ///
+    /// Jobs that finished are listed one last time and then removed from the table.
+    pub struct Jobs;
+
+    impl Jobs {
+        /// Create a new `Jobs` command.
+        pub fn new() -> Self {
+            Self
+        }
+
+        /// Run the `jobs` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let len = shell.jobs.len();
+            let mut lines = vec![];
+            for (i, job) in shell.jobs.iter_mut().enumerate() {
+                job.poll();
+                lines.push((job.id, job.describe(Shell::job_marker(i, len))));
+            }
+            // The table is ordered by when the jobs became current, but we list them by number.
+            lines.sort();
+            let mut stdout = String::new();
+            for (_, line) in lines {
+                stdout.push_str(&line);
+                stdout.push('\n');
+            }
+            shell
+                .jobs
+                .retain(|job| !matches!(job.state, JobState::Done(_)));
+            print(stdout)
+        }
+    }
+
+    /// The `fg` command continues a job in the foreground and waits for it.
+    ///
+    /// `fg` without an argument continues the current job, the one that `jobs`
+    /// marks with `+`. Like in bash, `%1` on its own is a shorthand for `fg %1`.
+    pub struct Fg {
+        args: Vec<String>,
+    }
+
+    impl Fg {
+        /// Create a new `Fg` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `fg` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let index = job_index("fg", shell, self.args.first())?;
+            let mut job = shell.jobs.remove(index);
+            // Print the command before it takes over the terminal.
+            println!("{}", job.command);
+            job.signal(Signal::SIGCONT)?;
+            job.state = JobState::Running;
+            status(shell.wait_in_foreground(job))
+        }
+    }
+
+    /// The `bg` command continues a stopped job in the background,
+    /// as if it had been started with `&`.
+    pub struct Bg {
+        args: Vec<String>,
+    }
+
+    impl Bg {
+        /// Create a new `Bg` command.
+        pub fn new(args: Vec<String>) -> Self {
+            Self { args }
+        }
+
+        /// Run the `bg` command.
+        pub fn run(self, shell: &mut Shell) -> Result<Option<Output>> {
+            let index = job_index("bg", shell, self.args.first())?;
+            let marker = Shell::job_marker(index, shell.jobs.len());
+            let job = &mut shell.jobs[index];
+            job.signal(Signal::SIGCONT)?;
+            job.state = JobState::Running;
+            print(format!("[{}]{marker} {} &\n", job.id, job.command))
+        }
+    }
+
+    /// Find the job that `spec` refers to, and return its index in the job table.
+    ///
+    /// `%1` is job 1, `%%` and `%+` are the current job, `%-` is the previous
+    /// job, and `%name` is the job whose command starts with `name`.
+    /// Without `spec`, it's the current job.
+    fn job_index(builtin: &str, shell: &Shell, spec: Option<&String>) -> Result<usize> {
+        let spec = spec.map_or("%+", String::as_str);
+        let name = spec.strip_prefix('%').unwrap_or(spec);
+        let len = shell.jobs.len();
+        let index = match name {
+            "" | "%" | "+" => len.checked_sub(1),
+            "-" => len.checked_sub(2),
+            _ => match name.parse::<usize>() {
+                Ok(id) => shell.jobs.iter().position(|job| job.id == id),
+                Err(_) => shell
+                    .jobs
+                    .iter()
+                    .position(|job| job.command.starts_with(name)),
+            },
+        };
+        index.ok_or_else(|| format!("{builtin}: {spec}: no such job").into())
+    }
+
+    /// Handle a `NAME` or `NAME=value` argument of `export` and `readonly`,
+    /// and return the name.
+    fn assign(builtin: &str, shell: &mut Shell, arg: &str) -> Result<String> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        if !crate::is_valid_name(name) {
+            return Err(format!("{builtin}: `{arg}': not a valid identifier").into());
+        }
+        if let Some(value) = value {
+            shell.assign(name, value.to_string())?;
+        }
+        Ok(name.to_string())
+    }
+
+    /// Quote `value` in single quotes, so that the shell reads it back unchanged.
+    /// A single quote inside the value becomes `'\''`.
+    fn quote(value: &str) -> String {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
 
-    #[cfg(windows)]
-    use std::os::windows::process::ExitStatusExt;
+    use std::os::unix::process::ExitStatusExt;
 
     // Store history file in current path. This is convenient for debugging purposes.
     // In a real shell, the history would be stored in a file in the user's home directory.
@@ -94,6 +860,11 @@ mod builtins {
             Self { history_path }
         }
 
+        /// The file that the history is stored in.
+        pub fn path(&self) -> &Path {
+            &self.history_path
+        }
+
         /// Add a command to the history.
         pub fn add(&self, command: &str) -> Result<()> {
             let mut history = std::fs::OpenOptions::new()
@@ -120,13 +891,143 @@ mod builtins {
 // This struct doesn't use lifetimes to keep the code simple.
 // You can try to use `&str` instead of `String`
 // to avoid unnecessary allocations. 👍
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 struct Cmd {
-    binary: String,
-    args: Vec<String>,
+    /// `NAME=value` assignments before the command, like in `RUST_LOG=debug cargo run`.
+    /// They only apply to the command, or set shell variables if there is no command.
+    assignments: Vec<Assignment>,
+    /// The binary and its arguments, before expansion.
+    /// A word can expand to any number of arguments, so we only know
+    /// which word is the binary after expanding them.
+    words: Vec<Word>,
+    redirects: Vec<Redirect>,
+}
+
+/// A `NAME=value` assignment.
+#[derive(PartialEq, Debug, Clone)]
+struct Assignment {
+    name: String,
+    value: Word,
+}
+
+/// A word of a command before expansion, like `"$HOME"/src`.
+///
+/// Quotes decide which parts of a word get expanded, so the lexer
+/// keeps track of them instead of removing them right away.
+#[derive(PartialEq, Debug, Clone, Default)]
+struct Word(Vec<WordPart>);
+
+#[derive(PartialEq, Debug, Clone)]
+enum WordPart {
+    /// Unquoted text.
+    Literal(String),
+    /// Text in single quotes or escaped with a backslash, which is never expanded.
+    Quoted(String),
+    /// Text in double quotes. Expansions inside of it are not split into multiple arguments.
+    DoubleQuoted(Vec<WordPart>),
+    /// A parameter expansion like `$HOME` or `${HOME:-/root}`.
+    Param(Param),
+    /// A tilde prefix like `~` or `~user`, which expands to a home directory.
+    /// The string is the text after the `~`.
+    Tilde(String),
+    /// An arithmetic expansion like `$((i + 1))`, which expands to the result.
+    /// The expression can contain expansions itself, like `$i`.
+    Arithmetic(Word),
+    /// A command substitution like `$(pwd)` or `` `pwd` ``,
+    /// which expands to the output of the commands.
+    CommandSub(Vec<Chain>),
+}
+
+/// A parameter expansion like `$HOME` or `${HOME:-/root}`.
+#[derive(PartialEq, Debug, Clone)]
+struct Param {
+    name: String,
+    op: ParamOp,
+}
+
+/// What to do with the value of a [`Param`].
+///
+/// The operators `-`, `=`, `?` and `+` can be prefixed with a colon.
+/// With a colon, an empty parameter is treated like an unset one.
+#[derive(PartialEq, Debug, Clone)]
+enum ParamOp {
+    /// `$name` or `${name}`
+    Value,
+    /// `${#name}`: the length of the value.
+    Length,
+    /// `${name:-word}`: `word` if the parameter is unset.
+    Default { word: Word, colon: bool },
+    /// `${name:=word}`: like `Default`, but also assigns `word` to the variable.
+    Assign { word: Word, colon: bool },
+    /// `${name:?word}`: fail with the message `word` if the parameter is unset.
+    Error { word: Word, colon: bool },
+    /// `${name:+word}`: `word` if the parameter is set.
+    Alternative { word: Word, colon: bool },
+    /// `${name#pattern}` removes the shortest matching prefix, `##` the longest.
+    RemovePrefix { pattern: Word, longest: bool },
+    /// `${name%pattern}` removes the shortest matching suffix, `%%` the longest.
+    RemoveSuffix { pattern: Word, longest: bool },
+    /// `${name/pattern/replacement}` and its variants `//`, `/#` and `/%`.
+    Replace {
+        pattern: Word,
+        replacement: Word,
+        mode: ReplaceMode,
+    },
+    /// `${name:offset}` or `${name:offset:length}`
+    Substring { offset: Word, length: Option<Word> },
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum ReplaceMode {
+    /// `/`: replace the first match.
+    First,
+    /// `//`: replace all matches.
+    All,
+    /// `/#`: replace a match at the start.
+    Prefix,
+    /// `/%`: replace a match at the end.
+    Suffix,
+}
+
+/// A redirection like `> file` or `2>&1`.
+#[derive(PartialEq, Debug, Clone)]
+struct Redirect {
+    /// The redirected file descriptor: 0 for stdin, 1 for stdout, 2 for stderr.
+    /// Other file descriptors like in `3< file` aren't supported.
+    fd: u32,
+    target: RedirectTarget,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum RedirectTarget {
+    /// `< file`
+    Read(Word),
+    /// `> file`, which truncates the file.
+    Write(Word),
+    /// `>> file`
+    Append(Word),
+    /// `>&fd` or `<&fd`, which makes the two file descriptors point to the same file.
+    Dup(u32),
+    /// `<<EOF` or `<<-EOF`, followed by the lines up to `EOF`.
+    HereDoc(Word),
+    /// `<<< word`, which feeds the word and a newline to stdin.
+    HereString(Word),
+}
+
+/// The input ended in the middle of a command, e.g. inside a here-document.
+/// The shell needs to read more lines to complete it.
+#[derive(Debug)]
+struct Incomplete(&'static str);
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unexpected end of input: unterminated {}", self.0)
+    }
 }
 
-#[derive(PartialEq, Debug)]
+impl std::error::Error for Incomplete {}
+
+#[derive(PartialEq, Debug, Clone)]
 enum Element {
     /// `|`
     Pipe,
@@ -136,288 +1037,5023 @@ enum Element {
     Or,
     /// Command.
     Cmd(Cmd),
+    /// `&` at the end of a chain, which runs it in the background.
+    Background,
 }
 
-/// Parse `[Element]`s from a string.
-struct Parser {
+/// A token produced by the [`Lexer`].
+#[derive(PartialEq, Debug, Clone)]
+enum Token {
+    /// A word like `"$HOME"/src`.
+    Word(Word),
+    /// An operator like `|`, `&&`, `||`, `;` or a redirection like `2>`.
+    Operator(String),
+    /// An unquoted newline, which separates commands just like `;`.
+    Newline,
+    /// The body of the here-document that belongs to the preceding `<<` operator.
+    HereDoc(Word),
+    /// The expression of an arithmetic command like `(( i++ ))`.
+    Arithmetic(Word),
+}
+
+/// Split a string into [`Token`]s, character by character.
+///
+/// In the previous blocks we used `split_whitespace`, which breaks as soon as
+/// an argument contains a space. The lexer understands POSIX quoting instead:
+///
+/// - Single quotes preserve every character literally: `'a b'` is one word.
+/// - Double quotes preserve whitespace, but a backslash still escapes
+///   `$`, `` ` ``, `"`, `\` and newline.
+/// - Outside of quotes, a backslash escapes the next character and
+///   a backslash followed by a newline is removed entirely.
+///
+/// The quotes end up in the [`Word`]s, because they decide which parts
+/// of a word are expanded later on. The lexer also reads the bodies of
+/// here-documents, which start on the line after their `<<` operator.
+/// If the input ends before a quote or here-document is closed,
+/// or right after a line continuation, it returns an [`Incomplete`] error.
+///
+/// Command substitutions like `$(pwd)` are tokenized by the same lexer,
+/// so quotes and nested substitutions inside of them just work.
+/// While inside of a substitution, an unquoted `)` ends it.
+struct Lexer {
+    chars: Vec<char>,
     current: usize,
-    tokens: Vec<String>,
+    /// How many command substitutions `$(...)` we are inside of.
+    depth: usize,
 }
 
-impl Parser {
-    fn new(chain: &str) -> Self {
+impl Lexer {
+    fn new(input: &str) -> Self {
         Self {
-            tokens: chain.split_whitespace().map(String::from).collect(),
+            chars: input.chars().collect(),
             current: 0,
+            depth: 0,
         }
     }
 
-    fn parse(mut self) -> Option<Chain> {
-        let mut elements = vec![];
-        while let Some(e) = self.parse_next() {
-            elements.push(e);
+    fn tokenize(mut self) -> Result<Vec<Token>> {
+        self.read_tokens()
+    }
+
+    /// Read tokens up to the end of the input,
+    /// or up to the `)` that closes the current command substitution.
+    fn read_tokens(&mut self) -> Result<Vec<Token>> {
+        let mut tokens = vec![];
+        // Here-documents waiting for their body: the index of their token,
+        // the delimiter, whether to strip tabs and whether the delimiter was quoted.
+        let mut heredocs: Vec<(usize, String, bool, bool)> = vec![];
+        while let Some(token) = self.next_token()? {
+            if token == Token::Newline {
+                for (i, delimiter, strip_tabs, quoted) in heredocs.drain(..) {
+                    let body = self.read_heredoc_body(&delimiter, strip_tabs)?;
+                    // A quoted delimiter, like `<<'EOF'`, disables expansions.
+                    tokens[i] = Token::HereDoc(if quoted {
+                        Word(vec![WordPart::Quoted(body)])
+                    } else {
+                        let parts = Lexer::new(&body).read_expandable(None)?;
+                        Word(vec![WordPart::DoubleQuoted(parts.0)])
+                    });
+                }
+            }
+            let strip_tabs = match &token {
+                Token::Operator(op) => match op.trim_start_matches(|c: char| c.is_ascii_digit()) {
+                    "<<" => Some(false),
+                    "<<-" => Some(true),
+                    _ => None,
+                },
+                _ => None,
+            };
+            tokens.push(token);
+            if let Some(strip_tabs) = strip_tabs {
+                let (delimiter, quoted) = self.read_heredoc_delimiter()?;
+                heredocs.push((tokens.len(), delimiter, strip_tabs, quoted));
+                tokens.push(Token::HereDoc(Word::default()));
+            }
         }
-        if !elements.is_empty() {
-            Some(Chain { elements })
-        } else {
-            None
+        if !heredocs.is_empty() {
+            return Err(Incomplete("here-document").into());
         }
+        Ok(tokens)
     }
 
-    fn parse_next(&mut self) -> Option<Element> {
-        let next = self.tokens.get(self.current).map(|s| s.to_string());
-        next.and_then(|next| {
-            self.current += 1;
-            match Element::parse_operator(&next) {
-                Some(operator) => Some(operator),
-                None => self.parse_cmd(next.to_string()).map(Element::Cmd),
-            }
-        })
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.current).copied()
     }
 
-    fn parse_cmd(&mut self, binary: String) -> Option<Cmd> {
-        let mut args: Vec<String> = vec![];
-        loop {
-            let next = self.tokens.get(self.current);
-            match next {
-                Some(token) if Element::is_operator(token) => {
-                    // found operator, so I already parsed all cmd
-                    break;
-                }
-                Some(token) => {
-                    args.push(token.to_string());
-                }
-                None => break,
-            }
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.chars.get(self.current + n).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace() && c != '\n') {
             self.current += 1;
         }
-        Some(Cmd { binary, args })
     }
-}
 
-#[derive(PartialEq, Debug)]
-struct Chain {
-    elements: Vec<Element>,
-}
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => return Ok(None),
+            Some('\n') => {
+                self.current += 1;
+                return Ok(Some(Token::Newline));
+            }
+            Some(')') if self.depth > 0 => return Ok(None),
+            Some('(') if self.peek_nth(1) == Some('(') => {
+                self.current += 2;
+                return Ok(Some(Token::Arithmetic(self.read_arithmetic()?)));
+            }
+            Some(_) => {}
+        }
+        if let Some(operator) = self.read_operator() {
+            return Ok(Some(Token::Operator(operator)));
+        }
+        self.read_word()
+            .map(|word| Some(Token::Word(word.mark_tildes(false))))
+    }
 
-impl Chain {
-    fn run(self) -> Option<Output> {
-        let mut prev_output: Option<Output> = None;
-        for e in self.elements {
-            match e {
-                Element::Cmd(cmd) => {
-                    prev_output = cmd.run(prev_output);
-                }
-                Element::Pipe => continue,
-                Element::And => {
-                    if !prev_output.as_ref()?.status.success() {
-                        break;
+    /// Read an operator if the input continues with one.
+    fn read_operator(&mut self) -> Option<String> {
+        // Redirections can start with a file descriptor number, like `2>`.
+        let digits = self.chars[self.current..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        let operator = match (self.peek_nth(digits)?, self.peek_nth(digits + 1)) {
+            ('>', Some('>')) => ">>",
+            ('>', Some('&')) => ">&",
+            ('>', _) => ">",
+            ('<', Some('<')) => match self.peek_nth(digits + 2) {
+                Some('<') => "<<<",
+                Some('-') => "<<-",
+                _ => "<<",
+            },
+            ('<', Some('&')) => "<&",
+            ('<', _) => "<",
+            // Digits not followed by a redirection are part of a word.
+            _ if digits > 0 => return None,
+            ('&', Some('&')) => "&&",
+            ('&', Some('>')) if self.peek_nth(2) == Some('>') => "&>>",
+            ('&', Some('>')) => "&>",
+            ('&', _) => "&",
+            ('|', Some('|')) => "||",
+            ('|', _) => "|",
+            (';', _) => ";",
+            _ => return None,
+        };
+        let fd: String = self.chars[self.current..self.current + digits]
+            .iter()
+            .collect();
+        let operator = format!("{fd}{operator}");
+        self.current += operator.len();
+        Some(operator)
+    }
+
+    /// Whether the character at the current position ends an unquoted word.
+    fn at_word_boundary(&self) -> bool {
+        match self.peek() {
+            None => true,
+            Some(c) if c.is_whitespace() => true,
+            Some('|' | ';' | '<' | '>' | '&') => true,
+            Some(')') => self.depth > 0,
+            Some(_) => false,
+        }
+    }
+
+    fn read_word(&mut self) -> Result<Word> {
+        let mut word = Word::default();
+        while !self.at_word_boundary() {
+            let c = self.chars[self.current];
+            self.current += 1;
+            match c {
+                '\'' => word.push(WordPart::Quoted(self.read_single_quoted()?)),
+                '"' => word.push(WordPart::DoubleQuoted(self.read_expandable(Some('"'))?.0)),
+                '\\' => match self.peek() {
+                    // Line continuation: drop both the backslash and the newline.
+                    // The shell reads one line at a time, so at the end of the input,
+                    // the command continues on the next line.
+                    Some('\n') if self.current + 1 == self.chars.len() => {
+                        return Err(Incomplete("line continuation").into())
                     }
-                }
-                Element::Or => {
-                    if prev_output.as_ref()?.status.success() {
-                        break;
+                    Some('\n') => self.current += 1,
+                    Some(escaped) => {
+                        word.push(WordPart::Quoted(escaped.to_string()));
+                        self.current += 1;
                     }
-                }
+                    None => return Err(Incomplete("line continuation").into()),
+                },
+                '$' => word.push(self.read_dollar()?),
+                '`' => word.push(WordPart::CommandSub(self.read_backquoted(false)?)),
+                c => word.push(WordPart::Literal(c.to_string())),
             }
         }
-        prev_output
+        Ok(word)
     }
-}
 
-impl Element {
-    fn parse_operator(token: &str) -> Option<Self> {
-        match token {
-            "|" => Some(Self::Pipe),
-            "&&" => Some(Self::And),
-            "||" => Some(Self::Or),
-            _ => None,
+    /// Read what follows a `$`: a parameter like `$HOME` or `${HOME}`,
+    /// or a command substitution like `$(pwd)`.
+    /// A `$` that doesn't start an expansion is taken literally.
+    fn read_dollar(&mut self) -> Result<WordPart> {
+        if self.peek() == Some('(') && self.peek_nth(1) == Some('(') {
+            self.current += 2;
+            return Ok(WordPart::Arithmetic(self.read_arithmetic()?));
+        }
+        if self.peek() == Some('(') {
+            self.current += 1;
+            return Ok(WordPart::CommandSub(self.read_command_sub()?));
+        }
+        if self.peek() == Some('{') {
+            self.current += 1;
+            return Ok(WordPart::Param(self.read_braced_param()?));
+        }
+        match self.read_param_name() {
+            Some(name) => Ok(WordPart::Param(Param {
+                name,
+                op: ParamOp::Value,
+            })),
+            None => Ok(WordPart::Literal("$".to_string())),
         }
     }
 
-    fn is_operator(token: &str) -> bool {
-        Self::parse_operator(token).is_some()
+    /// Read an arithmetic expression up to the closing `))`,
+    /// after the opening `$((` or `((`.
+    ///
+    /// The expression may contain parentheses and expansions like `$i`,
+    /// which are parsed like in a here-document.
+    fn read_arithmetic(&mut self) -> Result<Word> {
+        let start = self.current;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None => return Err(Incomplete("arithmetic expression").into()),
+                Some('(') => depth += 1,
+                Some(')') if depth > 0 => depth -= 1,
+                Some(')') if self.peek_nth(1) == Some(')') => break,
+                Some(')') => return Err("syntax error: expected `))`".into()),
+                Some(_) => {}
+            }
+            self.current += 1;
+        }
+        let expression: String = self.chars[start..self.current].iter().collect();
+        self.current += 2;
+        Lexer::new(&expression).read_expandable(None)
     }
-}
 
-impl Cmd {
-    fn run(&self, prev_output: Option<Output>) -> Option<Output> {
-        let result = match self.binary.as_ref() {
-            "cd" => {
-                let dir = self.args.get(0)?;
-                let dir = std::path::PathBuf::from(dir);
-                builtins::Cd::new(dir).run()
+    /// Read the commands of a command substitution, after the opening `$(`.
+    fn read_command_sub(&mut self) -> Result<Vec<Chain>> {
+        self.depth += 1;
+        let tokens = self.read_tokens();
+        self.depth -= 1;
+        let tokens = tokens?;
+        if self.peek() != Some(')') {
+            return Err(Incomplete("command substitution").into());
+        }
+        self.current += 1;
+        Parser { tokens, current: 0 }.parse()
+    }
+
+    /// Read the commands of a legacy command substitution like `` `pwd` ``,
+    /// after the opening backquote.
+    ///
+    /// Backquotes can't be nested without escaping them, so we read the
+    /// text up to the closing backquote, remove the backslashes in front
+    /// of `` ` ``, `$` and `\`, and parse the result as a command line.
+    /// Inside of double quotes, `\"` is unescaped too: `"`echo \"hi\"`"` is `hi`.
+    fn read_backquoted(&mut self, in_double_quotes: bool) -> Result<Vec<Chain>> {
+        let mut command = String::new();
+        loop {
+            match self.peek() {
+                Some('`') => break,
+                Some('\\')
+                    if matches!(self.peek_nth(1), Some('`' | '$' | '\\'))
+                        || (in_double_quotes && self.peek_nth(1) == Some('"')) =>
+                {
+                    command.push(self.chars[self.current + 1]);
+                    self.current += 2;
+                }
+                Some(c) => {
+                    command.push(c);
+                    self.current += 1;
+                }
+                None => return Err(Incomplete("backquote").into()),
             }
-            "exit" => {
-                let status = self.args.get(0).and_then(|s| s.parse().ok()).unwrap_or(0);
-                builtins::Exit::new(status).run()
+        }
+        self.current += 1;
+        chains_from_line(&command)
+    }
+
+    /// Read the name of a parameter: a variable name, a special parameter
+    /// like `?`, or a positional parameter like `1`.
+    ///
+    /// Without braces, positional parameters only have one digit:
+    /// `$10` is `$1` followed by `0`, but `${10}` is the tenth parameter.
+    fn read_param_name(&mut self) -> Option<String> {
+        let start = self.current;
+        let is_name_char = |c: char| c == '_' || c.is_ascii_alphanumeric();
+        match self.peek()? {
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(is_name_char) {
+                    self.current += 1;
+                }
             }
-            "history" => builtins::History::new().run(),
-            _ => self.run_external(prev_output),
+            c if is_special_param(&c.to_string()) => self.current += 1,
+            _ => return None,
+        }
+        Some(self.chars[start..self.current].iter().collect())
+    }
+
+    /// Read a parameter expansion in braces like `${HOME:-/root}`,
+    /// after the opening `${`.
+    fn read_braced_param(&mut self) -> Result<Param> {
+        let bad_substitution = || "bad substitution".into();
+        // `${#}` is the number of positional parameters,
+        // while `${#name}` is the length of `name`.
+        let length = self.peek() == Some('#') && self.peek_nth(1) != Some('}');
+        if length {
+            self.current += 1;
+        }
+        let Some(mut name) = self.read_param_name() else {
+            return Err(bad_substitution());
         };
+        while name.starts_with(|c: char| c.is_ascii_digit())
+            && self.peek().is_some_and(|c| c.is_ascii_digit())
+        {
+            name.push(self.chars[self.current]);
+            self.current += 1;
+        }
 
-        match result {
-            Ok(output) => {
-                if let Some(output) = &output {
-                    // Print stderr (if any)
-                    std::io::stderr().write_all(&output.stderr).unwrap();
+        let op = match (self.peek(), self.peek_nth(1)) {
+            _ if length => ParamOp::Length,
+            (Some('}'), _) => ParamOp::Value,
+            (Some(':'), Some(op @ ('-' | '=' | '?' | '+'))) => {
+                self.current += 2;
+                self.read_param_test(op, true)?
+            }
+            (Some(op @ ('-' | '=' | '?' | '+')), _) => {
+                self.current += 1;
+                self.read_param_test(op, false)?
+            }
+            (Some(':'), _) => {
+                self.current += 1;
+                let offset = self.read_param_word(&[':', '}'])?;
+                let length = if self.peek() == Some(':') {
+                    self.current += 1;
+                    Some(self.read_param_word(&['}'])?)
+                } else {
+                    None
+                };
+                ParamOp::Substring { offset, length }
+            }
+            (Some(op @ ('#' | '%')), next) => {
+                let longest = next == Some(op);
+                self.current += if longest { 2 } else { 1 };
+                let pattern = self.read_param_word(&['}'])?;
+                if op == '#' {
+                    ParamOp::RemovePrefix { pattern, longest }
+                } else {
+                    ParamOp::RemoveSuffix { pattern, longest }
                 }
-                output
             }
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                None
+            (Some('/'), next) => {
+                self.current += 1;
+                let mode = match next {
+                    Some('/') => ReplaceMode::All,
+                    Some('#') => ReplaceMode::Prefix,
+                    Some('%') => ReplaceMode::Suffix,
+                    _ => ReplaceMode::First,
+                };
+                if mode != ReplaceMode::First {
+                    self.current += 1;
+                }
+                let pattern = self.read_param_word(&['/', '}'])?;
+                let mut replacement = Word::default();
+                if self.peek() == Some('/') {
+                    self.current += 1;
+                    replacement = self.read_param_word(&['}'])?;
+                }
+                ParamOp::Replace {
+                    pattern,
+                    replacement,
+                    mode,
+                }
             }
+            _ => return Err(bad_substitution()),
+        };
+        if self.peek() != Some('}') {
+            return Err(bad_substitution());
         }
+        self.current += 1;
+        Ok(Param { name, op })
     }
 
-    fn run_external(&self, prev_output: Option<Output>) -> Result<Option<Output>> {
-        let mut command = Command::new(&self.binary);
-        command.args(&self.args);
+    /// Read the word of a `-`, `=`, `?` or `+` parameter expansion.
+    fn read_param_test(&mut self, op: char, colon: bool) -> Result<ParamOp> {
+        let word = self.read_param_word(&['}'])?;
+        Ok(match op {
+            '-' => ParamOp::Default { word, colon },
+            '=' => ParamOp::Assign { word, colon },
+            '?' => ParamOp::Error { word, colon },
+            _ => ParamOp::Alternative { word, colon },
+        })
+    }
 
-        if prev_output.is_some() {
-            command.stdin(Stdio::piped());
+    /// Read a word inside of `${...}` up to one of the `ends` characters.
+    /// Whitespace doesn't end the word here, but quotes and expansions work as usual.
+    fn read_param_word(&mut self, ends: &[char]) -> Result<Word> {
+        let mut word = Word::default();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err("bad substitution: missing `}`".into());
+            };
+            if ends.contains(&c) {
+                return Ok(word);
+            }
+            self.current += 1;
+            match c {
+                '\'' => word.push(WordPart::Quoted(self.read_single_quoted()?)),
+                '"' => word.push(WordPart::DoubleQuoted(self.read_expandable(Some('"'))?.0)),
+                '\\' => {
+                    if let Some(escaped) = self.peek() {
+                        word.push(WordPart::Quoted(escaped.to_string()));
+                        self.current += 1;
+                    }
+                }
+                '$' => word.push(self.read_dollar()?),
+                '`' => word.push(WordPart::CommandSub(self.read_backquoted(false)?)),
+                c => word.push(WordPart::Literal(c.to_string())),
+            }
         }
+    }
 
-        let mut child = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+    /// Read the delimiter of a here-document, like `EOF` in `<<EOF`,
+    /// and whether it was quoted.
+    fn read_heredoc_delimiter(&mut self) -> Result<(String, bool)> {
+        self.skip_whitespace();
+        let word = self.read_word()?;
+        match word.literal() {
+            Some(delimiter) if !word.0.is_empty() => Ok((delimiter, word.is_quoted())),
+            _ => Err("syntax error: expected a delimiter after `<<`".into()),
+        }
+    }
 
-        if let Some(prev_output) = prev_output {
-            if let Some(mut stdin) = child.stdin.take() {
-                stdin.write_all(&prev_output.stdout)?;
+    /// Read the lines of a here-document up to the `delimiter` line.
+    ///
+    /// With `strip_tabs` (`<<-`), leading tabs are removed from every line,
+    /// so that here-documents can be indented in scripts.
+    fn read_heredoc_body(&mut self, delimiter: &str, strip_tabs: bool) -> Result<String> {
+        let mut body = String::new();
+        loop {
+            if self.peek().is_none() {
+                return Err(Incomplete("here-document").into());
             }
+            let mut line = String::new();
+            while let Some(c) = self.peek() {
+                self.current += 1;
+                if c == '\n' {
+                    break;
+                }
+                line.push(c);
+            }
+            let line = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                &line
+            };
+            if line == delimiter {
+                return Ok(body);
+            }
+            body.push_str(line);
+            body.push('\n');
         }
+    }
 
-        let output = child.wait_with_output()?;
-        Ok(Some(output))
+    fn read_single_quoted(&mut self) -> Result<String> {
+        let mut word = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') => {
+                    self.current += 1;
+                    return Ok(word);
+                }
+                Some(c) => {
+                    word.push(c);
+                    self.current += 1;
+                }
+                None => return Err(Incomplete("single quote").into()),
+            }
+        }
     }
-}
 
-fn main() {
-    let history = builtins::History::new();
-    loop {
-        show_prompt();
-        let line = read_line();
-        history.add(&line.trim()).expect("Cannot open history file");
-        let chains = chains_from_line(line);
-        for chain in chains {
-            let output = chain.run();
-            if let Some(output) = output {
-                std::io::stdout().write_all(&output.stdout).unwrap();
+    /// Read text in which parameters are expanded, up to the `end` character.
+    ///
+    /// This is either the closing `"` of a double quoted string, or `None`
+    /// for the body of a here-document, which is read up to the end of the input.
+    fn read_expandable(&mut self, end: Option<char>) -> Result<Word> {
+        let mut word = Word::default();
+        loop {
+            match self.peek() {
+                Some(c) if Some(c) == end => {
+                    self.current += 1;
+                    return Ok(word);
+                }
+                Some('\\') => {
+                    self.current += 1;
+                    match self.peek() {
+                        Some('\n') => self.current += 1,
+                        Some(c) if matches!(c, '$' | '`' | '\\') || Some(c) == end => {
+                            word.push(WordPart::Literal(c.to_string()));
+                            self.current += 1;
+                        }
+                        // Other backslashes are kept.
+                        _ => word.push(WordPart::Literal("\\".to_string())),
+                    }
+                }
+                Some('$') => {
+                    self.current += 1;
+                    word.push(self.read_dollar()?);
+                }
+                Some('`') => {
+                    self.current += 1;
+                    let in_double_quotes = end == Some('"');
+                    word.push(WordPart::CommandSub(
+                        self.read_backquoted(in_double_quotes)?,
+                    ));
+                }
+                Some(c) => {
+                    word.push(WordPart::Literal(c.to_string()));
+                    self.current += 1;
+                }
+                None if end.is_none() => return Ok(word),
+                None => return Err(Incomplete("double quote").into()),
             }
         }
     }
 }
 
-/// If `stdout` is printed to a terminal, print a prompt.
-/// Otherwise, do nothing. This allows to redirect the shell `stdout`
-/// to a file or another process, without the prompt being printed.
-fn show_prompt() {
-    let mut stdout = std::io::stdout();
-    if stdout.is_terminal() {
-        write!(stdout, "> ").unwrap();
-        // Flush stdout to ensure the prompt is displayed.
-        stdout.flush().expect("can't flush stdout");
+/// Parse [`Chain`]s from a string.
+///
+/// The whole line is tokenized once, so `;` only separates chains when
+/// the lexer sees it outside of quotes and escapes.
+struct Parser {
+    current: usize,
+    tokens: Vec<Token>,
+}
+
+impl Parser {
+    fn new(chain: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: Lexer::new(chain).tokenize()?,
+            current: 0,
+        })
+    }
+
+    fn parse(mut self) -> Result<Vec<Chain>> {
+        let mut chains = vec![];
+        while self.current < self.tokens.len() {
+            if let Some(chain) = self.parse_chain()? {
+                chains.push(chain);
+            }
+        }
+        Ok(chains)
+    }
+
+    fn parse_chain(&mut self) -> Result<Option<Chain>> {
+        let mut elements = vec![];
+        while let Some(e) = self.parse_next()? {
+            // `&` terminates the chain, just like `;`.
+            let background = e == Element::Background;
+            elements.push(e);
+            if background {
+                break;
+            }
+        }
+        // Every operator needs a command on its left, and `|`, `&&` and `||`
+        // need one on their right too: `| ls`, `ls &&` and `&` are errors.
+        let mut needs_cmd = true;
+        for e in &elements {
+            match e {
+                Element::Cmd(_) => needs_cmd = false,
+                _ if needs_cmd => {
+                    let token = e.operator();
+                    return Err(format!("syntax error near unexpected token `{token}'").into());
+                }
+                _ => needs_cmd = true,
+            }
+        }
+        match elements.last() {
+            None => Ok(None),
+            Some(e @ (Element::Pipe | Element::And | Element::Or)) => {
+                let token = e.operator();
+                Err(format!("syntax error: expected a command after `{token}'").into())
+            }
+            Some(_) => Ok(Some(Chain { elements })),
+        }
+    }
+
+    fn parse_next(&mut self) -> Result<Option<Element>> {
+        let Some(next) = self.tokens.get(self.current) else {
+            return Ok(None);
+        };
+        match next {
+            // `;` and newlines terminate the current chain.
+            Token::Newline => {
+                self.current += 1;
+                Ok(None)
+            }
+            Token::Operator(operator) if operator == ";" => {
+                self.current += 1;
+                Ok(None)
+            }
+            // A command can start with a redirection, like `< input.txt sort`.
+            Token::Operator(operator) if !Redirect::is_operator(operator) => {
+                self.current += 1;
+                Ok(Element::parse_operator(operator))
+            }
+            _ => Ok(self.parse_cmd()?.map(Element::Cmd)),
+        }
+    }
+
+    fn parse_cmd(&mut self) -> Result<Option<Cmd>> {
+        let mut words: Vec<Word> = vec![];
+        let mut redirects = vec![];
+        loop {
+            let next = self.tokens.get(self.current).cloned();
+            match next {
+                Some(Token::Operator(operator)) if Redirect::is_operator(&operator) => {
+                    self.current += 1;
+                    let target = self.tokens.get(self.current).cloned();
+                    redirects.extend(Redirect::parse(&operator, target)?);
+                }
+                Some(Token::Operator(_) | Token::Newline | Token::HereDoc(_)) => {
+                    // found operator, so I already parsed all cmd
+                    break;
+                }
+                Some(Token::Word(word)) => {
+                    words.push(word);
+                }
+                // `(( expression ))` is the same as `let "expression"`.
+                Some(Token::Arithmetic(expression)) if words.is_empty() => {
+                    words.push(Word(vec![WordPart::Literal("let".to_string())]));
+                    words.push(Word(vec![WordPart::DoubleQuoted(expression.0)]));
+                }
+                Some(Token::Arithmetic(_)) => return Err("syntax error near `((`".into()),
+                None => break,
+            }
+            self.current += 1;
+        }
+        if words.is_empty() && redirects.is_empty() {
+            return Ok(None);
+        }
+        // Leading `NAME=value` words are assignments, the rest is the command.
+        // `echo A=1` passes `A=1` as an argument, though.
+        let count = words
+            .iter()
+            .take_while(|word| word.as_assignment().is_some())
+            .count();
+        let assignments = words
+            .drain(..count)
+            .filter_map(|word| word.as_assignment())
+            .collect();
+        Ok(Some(Cmd {
+            assignments,
+            words,
+            redirects,
+        }))
     }
 }
 
-fn read_line() -> String {
-    let mut line = String::new();
-    io::stdin()
-        .read_line(&mut line)
-        .expect("failed to read line from stdin");
-    line
+impl Redirect {
+    fn is_operator(token: &str) -> bool {
+        token
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .starts_with(['<', '>'])
+            || token.starts_with("&>")
+    }
+
+    /// Parse a redirection operator like `2>` and its target.
+    ///
+    /// `&> file` is a shorthand for `> file 2>&1`, so it results in two redirects.
+    /// We only pass stdin, stdout and stderr to commands, so file descriptors
+    /// other than 0, 1 and 2 are an error, like `3< file` or `>&3`.
+    fn parse(operator: &str, target: Option<Token>) -> Result<Vec<Self>> {
+        let digits = operator.chars().take_while(|c| c.is_ascii_digit()).count();
+        let fd = match &operator[..digits] {
+            "" => None,
+            // Numbers that are too large for a file descriptor are unsupported too.
+            number => match number.parse() {
+                Ok(fd) => Some(Self::check_fd(fd)?),
+                Err(_) => return Err(format!("{number}: unsupported file descriptor").into()),
+            },
+        };
+        let target = match target {
+            Some(Token::Word(target)) => target,
+            Some(Token::HereDoc(heredoc)) => {
+                return Ok(vec![Self::new(
+                    fd.unwrap_or(0),
+                    RedirectTarget::HereDoc(heredoc),
+                )])
+            }
+            _ => {
+                return Err(format!("syntax error: expected a file name after `{operator}`").into())
+            }
+        };
+        let fd_target = || {
+            let fd = target
+                .literal()
+                .and_then(|target| target.parse().ok())
+                .ok_or_else(|| format!("`{operator}` expects a file descriptor"))?;
+            Self::check_fd(fd)
+        };
+        let redirects = match &operator[digits..] {
+            "<" => vec![Self::new(fd.unwrap_or(0), RedirectTarget::Read(target))],
+            ">" => vec![Self::new(fd.unwrap_or(1), RedirectTarget::Write(target))],
+            ">>" => vec![Self::new(fd.unwrap_or(1), RedirectTarget::Append(target))],
+            "<<<" => vec![Self::new(
+                fd.unwrap_or(0),
+                RedirectTarget::HereString(target),
+            )],
+            "<&" => vec![Self::new(
+                fd.unwrap_or(0),
+                RedirectTarget::Dup(fd_target()?),
+            )],
+            ">&" => vec![Self::new(
+                fd.unwrap_or(1),
+                RedirectTarget::Dup(fd_target()?),
+            )],
+            "&>" => vec![
+                Self::new(1, RedirectTarget::Write(target)),
+                Self::new(2, RedirectTarget::Dup(1)),
+            ],
+            "&>>" => vec![
+                Self::new(1, RedirectTarget::Append(target)),
+                Self::new(2, RedirectTarget::Dup(1)),
+            ],
+            _ => return Err(format!("unknown redirection `{operator}`").into()),
+        };
+        Ok(redirects)
+    }
+
+    fn check_fd(fd: u32) -> Result<u32> {
+        match fd {
+            0..=2 => Ok(fd),
+            _ => Err(format!("{fd}: unsupported file descriptor").into()),
+        }
+    }
+
+    fn new(fd: u32, target: RedirectTarget) -> Self {
+        Self { fd, target }
+    }
 }
 
-fn chains_from_line(line: String) -> Vec<Chain> {
-    // For simplicity's sake, this workshop uses the split function.
-    // This is inefficient because it parses the whole line.
-    // If you feel adventurous, try to parse the line character by character instead. 🤠
-    line.split(';')
-        .map(|s| s.to_string())
-        .filter_map(|s| Parser::new(&s).parse())
-        .collect()
+#[derive(PartialEq, Debug, Clone)]
+struct Chain {
+    elements: Vec<Element>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Chain {
+    /// Run the chain and store its exit status in the shell, for `$?`.
+    ///
+    /// `&&` runs the next pipeline only if the previous one succeeded and
+    /// `||` only if it failed. A skipped pipeline keeps the previous status,
+    /// so `false && a || b` runs `b`.
+    fn run(self, shell: &mut Shell, stdout: &Io) {
+        if self.elements.last() == Some(&Element::Background) {
+            return self.run_in_background(shell, stdout);
+        }
+        let mut pipeline = vec![];
+        let mut run_next = true;
+        for e in self.elements {
+            match e {
+                Element::Cmd(cmd) => pipeline.push(cmd),
+                Element::Pipe | Element::Background => continue,
+                Element::And | Element::Or => {
+                    let pipeline = std::mem::take(&mut pipeline);
+                    if run_next {
+                        shell.last_status = Self::run_pipeline(pipeline, shell, stdout);
+                    }
+                    let success = shell.last_status == 0;
+                    run_next = if e == Element::And { success } else { !success };
+                    // After `exit` in a command substitution, nothing else runs.
+                    run_next &= shell.exit_status.is_none();
+                }
+            }
+        }
+        if run_next && !pipeline.is_empty() {
+            shell.last_status = Self::run_pipeline(pipeline, shell, stdout);
+        }
+    }
 
-    fn parse_chains(line: &str) -> Vec<Chain> {
-        chains_from_line(line.to_string())
+    /// Run a chain that ends with `&` in a subshell without waiting for it,
+    /// and add the subshell to the job table of the shell.
+    ///
+    /// Like a real shell, we `fork` the shell itself, so the subshell runs the
+    /// whole chain, like `make && ./run &`, with a copy of the shell's state.
+    /// Builtins change the copy: `cd /tmp &` and `exit &` leave the shell alone.
+    fn run_in_background(mut self, shell: &mut Shell, stdout: &Io) {
+        self.elements.pop();
+        // Whatever is still buffered would be printed by both processes.
+        let _ = io::stdout().flush();
+        // SAFETY: the subshell only continues on the thread that forked it.
+        // The other threads of the shell only copy data between pipes,
+        // so they don't hold any locks that the subshell needs.
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => self.run_subshell(shell, stdout),
+            Ok(ForkResult::Parent { child }) => {
+                let pgid = shell.pgid.map(|_| {
+                    // Both processes set the group, so it's set before either goes on.
+                    let _ = setpgid(child, child);
+                    child
+                });
+                let job = Job {
+                    id: 0,
+                    command: self.to_string(),
+                    processes: vec![Process::Child {
+                        pid: child,
+                        stopped: false,
+                    }],
+                    pgid,
+                    state: JobState::Running,
+                };
+                let id = shell.add_job(job);
+                shell.last_background_pid = Some(child.as_raw() as u32);
+                eprintln!("[{id}] {child}");
+                shell.last_status = 0;
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                shell.last_status = 1;
+            }
+        }
     }
 
-    #[test]
-    fn no_cmd_is_parsed_from_empty_line() {
-        assert_eq!(parse_chains(""), vec![]);
+    /// Run the chain in a forked subshell and exit with its status.
+    ///
+    /// The subshell has its own process group, so that Ctrl-C and Ctrl-Z
+    /// in the terminal don't reach it, but no job control of its own.
+    /// It doesn't read from the terminal, which belongs to the foreground.
+    fn run_subshell(self, shell: &mut Shell, stdout: &Io) -> ! {
+        if shell.pgid.take().is_some() {
+            let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+        }
+        let _ = reset_signals();
+        // The jobs of the shell aren't children of the subshell.
+        shell.jobs.clear();
+        match File::open("/dev/null") {
+            Ok(null) => {
+                let _ = dup2(null.as_raw_fd(), io::stdin().as_raw_fd());
+            }
+            Err(e) => eprintln!("Error: /dev/null: {}", e),
+        }
+        self.run(shell, stdout);
+        let _ = io::stdout().flush();
+        std::process::exit(shell.last_status);
     }
 
-    #[test]
-    fn cmd_with_no_args_is_parsed() {
-        assert_eq!(
-            parse_chains("ls"),
-            vec![Chain {
-                elements: vec![Element::Cmd(Cmd {
-                    binary: "ls".to_string(),
-                    args: vec![]
-                }),]
-            },]
-        );
+    /// Run the chains of a command substitution like `$(pwd)`
+    /// and return their output without trailing newlines.
+    ///
+    /// A real shell runs the substitution in a forked subshell, so it can't
+    /// change the state of the shell. We run it in the same process instead,
+    /// and restore the state afterwards: `$(cd /; x=1)` changes neither the
+    /// current directory nor `x`, and `$(exit 1)` only ends the substitution.
+    /// The status of the substitution is left in `$?`.
+    fn capture(chains: &[Chain], shell: &mut Shell) -> Result<String> {
+        let cwd = std::env::current_dir();
+        let vars = shell.vars.clone();
+        let exported = shell.exported.clone();
+        let readonly = shell.readonly.clone();
+        let positional = shell.positional.clone();
+        let options = shell.options.clone();
+        let dir_stack = shell.dir_stack.clone();
+        let in_substitution = std::mem::replace(&mut shell.in_substitution, true);
+
+        let output = Self::output(chains, shell);
+
+        shell.in_substitution = in_substitution;
+        shell.exit_status = None;
+        if let Ok(cwd) = cwd {
+            if let Err(e) = std::env::set_current_dir(&cwd) {
+                eprintln!("Error: {}: {}", cwd.display(), e);
+            }
+        }
+        shell.vars = vars;
+        shell.exported = exported;
+        shell.readonly = readonly;
+        shell.positional = positional;
+        shell.options = options;
+        shell.dir_stack = dir_stack;
+        output
     }
 
-    #[test]
-    fn cmd_with_args_is_parsed() {
-        assert_eq!(
-            parse_chains("ls -l"),
-            vec![Chain {
-                elements: vec![Element::Cmd(Cmd {
-                    binary: "ls".to_string(),
-                    args: vec!["-l".to_string()]
-                })]
-            }]
-        );
+    /// Run the chains in the shell and return their output without trailing newlines.
+    ///
+    /// The output is read by a separate thread while the commands are running,
+    /// so that they don't block once the pipe buffer is full.
+    fn output(chains: &[Chain], shell: &mut Shell) -> Result<String> {
+        let (mut reader, writer) = io::pipe()?;
+        let output = thread::spawn(move || {
+            let mut output = vec![];
+            reader.read_to_end(&mut output).map(|_| output)
+        });
+        let stdout = Io::PipeWriter(writer);
+        for chain in chains {
+            if shell.exit_status.is_some() {
+                break;
+            }
+            chain.clone().run(shell, &stdout);
+        }
+        // Close our end of the pipe, so the reader sees the end of the output.
+        drop(stdout);
+        let output = output
+            .join()
+            .map_err(|_| "failed to read the output of a command substitution")??;
+        let output = String::from_utf8_lossy(&output);
+        Ok(output.trim_end_matches('\n').to_string())
     }
 
-    #[test]
-    fn cmds_are_parsed() {
-        assert_eq!(
-            parse_chains("ls; echo hello"),
-            vec![
-                Chain {
-                    elements: vec![Element::Cmd(Cmd {
-                        binary: "ls".to_string(),
-                        args: vec![]
-                    }),]
-                },
-                Chain {
-                    elements: vec![Element::Cmd(Cmd {
-                        binary: "echo".to_string(),
-                        args: vec!["hello".to_string()]
-                    }),]
-                },
-            ]
-        );
+    /// Run the commands of a pipeline like `ls | grep foo | wc -l`
+    /// and return the exit status of the last command.
+    ///
+    /// All commands are started before we wait for any of them, and each
+    /// command's stdout is connected to the next command's stdin with an OS
+    /// pipe. This way, data streams through the pipeline while the commands
+    /// are running: `yes | head -1` terminates and memory usage stays bounded.
+    fn run_pipeline(cmds: Vec<Cmd>, shell: &mut Shell, stdout: &Io) -> i32 {
+        let job = Self::start_pipeline(cmds, shell, Io::Stdin, stdout);
+        shell.wait_in_foreground(job)
     }
 
-    #[test]
-    fn pipe_is_parsed() {
-        assert_eq!(
-            parse_chains("ls | wc -l"),
-            vec![Chain {
-                elements: vec![
-                    Element::Cmd(Cmd {
-                        binary: "ls".to_string(),
-                        args: vec![]
-                    }),
-                    Element::Pipe,
-                    Element::Cmd(Cmd {
-                        binary: "wc".to_string(),
-                        args: vec!["-l".to_string()]
-                    }),
-                ]
+    /// Start all commands of a pipeline, with `stdin` connected to the first one,
+    /// and return them as a job that isn't in the job table yet.
+    ///
+    /// With job control, the first external command starts a new process group,
+    /// which the other commands join. This way, Ctrl-Z stops the whole pipeline.
+    fn start_pipeline(cmds: Vec<Cmd>, shell: &mut Shell, mut stdin: Io, stdout: &Io) -> Job {
+        let mut processes = vec![];
+        let mut command_lines = vec![];
+        let mut pgid = None;
+        for (i, cmd) in cmds.iter().enumerate() {
+            let is_last = i == cmds.len() - 1;
+            let ios: Result<(Io, Io)> = if is_last {
+                stdout.try_clone().map(|stdout| (stdout, Io::Null))
+            } else {
+                io::pipe()
+                    .map(|(reader, writer)| (Io::PipeWriter(writer), Io::PipeReader(reader)))
+                    .map_err(Into::into)
+            };
+            let (stdout, next_stdin) = ios.unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                (Io::Null, Io::Null)
+            });
+            // A process group of 0 means a new group with the pid of the process.
+            let group = shell.pgid.map(|_| pgid.unwrap_or(Pid::from_raw(0)));
+            // If the command fails to start, the next command reads from
+            // a pipe without a writer, so it sees an empty stdin like in bash.
+            let (process, command_line) = cmd
+                .run(
+                    shell,
+                    std::mem::replace(&mut stdin, next_stdin),
+                    stdout,
+                    group,
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    (Process::Finished(1), String::new())
+                });
+            if shell.pgid.is_some() && pgid.is_none() {
+                pgid = process.id().map(|pid| Pid::from_raw(pid as i32));
+            }
+            processes.push(process);
+            command_lines.push(command_line);
+        }
+        Job {
+            id: 0,
+            command: command_lines.join(" | "),
+            processes,
+            pgid,
+            state: JobState::Running,
+        }
+    }
+}
+
+/// A command of a pipeline that has been started.
+enum Process {
+    /// An external command or a subshell, which may still be running or be stopped.
+    Child { pid: Pid, stopped: bool },
+    /// A command that already ran to completion, like a builtin,
+    /// with its exit status.
+    Finished(i32),
+}
+
+impl Process {
+    /// Wait until the process finished or stopped, and return its new state.
+    fn wait(&mut self) -> Result<JobState> {
+        self.update(WaitPidFlag::WUNTRACED)
+    }
+
+    /// Return the state of the process, without waiting for it.
+    ///
+    /// This also reaps a finished process, so it doesn't stay around as a zombie.
+    fn poll(&mut self) -> Result<JobState> {
+        self.update(WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED | WaitPidFlag::WNOHANG)
+    }
+
+    /// Call `waitpid`, which reports a stopped process as well with `WUNTRACED`.
+    ///
+    /// The standard library's `Child::wait` would block until the process exits,
+    /// even if Ctrl-Z stopped it, so we use `waitpid` from `nix` instead.
+    fn update(&mut self, flags: WaitPidFlag) -> Result<JobState> {
+        let (pid, stopped) = match self {
+            Process::Child { pid, stopped } => (*pid, stopped),
+            Process::Finished(status) => return Ok(JobState::Done(*status)),
+        };
+        // `waitpid` fails with `EINTR` if a signal handler of the shell ran, like for Ctrl-C.
+        let status = loop {
+            match waitpid(pid, Some(flags)) {
+                Err(Errno::EINTR) => continue,
+                result => break result?,
+            }
+        };
+        let status = match status {
+            WaitStatus::Exited(_, code) => code,
+            WaitStatus::Signaled(_, signal, _) => signal_status(signal as i32),
+            WaitStatus::Stopped(..) => {
+                *stopped = true;
+                return Ok(JobState::Stopped);
+            }
+            WaitStatus::Continued(_) => {
+                *stopped = false;
+                return Ok(JobState::Running);
+            }
+            // Nothing changed.
+            _ if *stopped => return Ok(JobState::Stopped),
+            _ => return Ok(JobState::Running),
+        };
+        *self = Process::Finished(status);
+        Ok(JobState::Done(status))
+    }
+
+    /// The process ID of an external command that didn't finish yet.
+    fn id(&self) -> Option<u32> {
+        match self {
+            Process::Child { pid, .. } => Some(pid.as_raw() as u32),
+            Process::Finished(_) => None,
+        }
+    }
+}
+
+/// The state of a job, as `jobs` shows it.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum JobState {
+    Running,
+    /// Stopped by a signal like `SIGTSTP` from Ctrl-Z, until `fg` or `bg` continues it.
+    Stopped,
+    /// All processes finished, with the exit status of the last one.
+    Done(i32),
+}
+
+/// A pipeline that was started by the shell.
+///
+/// Jobs in the job table run in the background, started with `&`,
+/// or were stopped while they ran in the foreground.
+struct Job {
+    /// The number of the job, which `jobs` shows as `[1]` and `fg %1` refers to.
+    /// It's 0 until the job is added to the job table.
+    id: usize,
+    /// The command line of the pipeline or background chain, like `sleep 10 | cat`.
+    command: String,
+    processes: Vec<Process>,
+    /// The process group of the pipeline, if job control is enabled.
+    pgid: Option<Pid>,
+    state: JobState,
+}
+
+impl Job {
+    /// Update the state of the job from the states of its processes,
+    /// without waiting for them.
+    fn poll(&mut self) -> JobState {
+        if !matches!(self.state, JobState::Done(_)) {
+            let states: Vec<JobState> = self
+                .processes
+                .iter_mut()
+                .map(|process| {
+                    process.poll().unwrap_or_else(|e| {
+                        eprintln!("Error: {}", e);
+                        JobState::Done(1)
+                    })
+                })
+                .collect();
+            self.state = if states.contains(&JobState::Stopped) {
+                JobState::Stopped
+            } else if states.contains(&JobState::Running) {
+                JobState::Running
+            } else {
+                states.last().copied().unwrap_or(JobState::Done(0))
+            };
+        }
+        self.state
+    }
+
+    /// Wait until all processes finished, or one of them stopped.
+    fn wait(&mut self) -> JobState {
+        let mut state = JobState::Done(0);
+        for process in &mut self.processes {
+            state = process.wait().unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                JobState::Done(1)
+            });
+            if state == JobState::Stopped {
+                break;
+            }
+        }
+        self.state = state;
+        state
+    }
+
+    /// Send `signal` to all processes of the job, like `SIGCONT` to continue it.
+    fn signal(&self, signal: Signal) -> Result<()> {
+        match self.pgid {
+            Some(pgid) => killpg(pgid, signal)?,
+            None => {
+                for pid in self.processes.iter().filter_map(Process::id) {
+                    kill(Pid::from_raw(pid as i32), signal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A line like `[1]+  Running                 sleep 10 &`, the way `bash` shows jobs.
+    ///
+    /// `+` marks the current job, the one started last, and `-` the previous one.
+    fn describe(&self, marker: char) -> String {
+        let (state, suffix) = match self.state {
+            JobState::Running => ("Running".to_string(), " &"),
+            JobState::Stopped => ("Stopped".to_string(), ""),
+            JobState::Done(0) => ("Done".to_string(), ""),
+            JobState::Done(status) => (format!("Exit {status}"), ""),
+        };
+        format!("[{}]{marker}  {state:<24}{}{suffix}", self.id, self.command)
+    }
+}
+
+/// Restore the default handlers of the signals that the shell ignores.
+///
+/// This runs in the child process right before `exec`. Ignored signals stay
+/// ignored across `exec`, so without this, Ctrl-Z couldn't stop our commands
+/// and Ctrl-\ couldn't quit them.
+fn reset_signals() -> io::Result<()> {
+    let signals = [
+        Signal::SIGINT,
+        Signal::SIGQUIT,
+        Signal::SIGTSTP,
+        Signal::SIGTTIN,
+        Signal::SIGTTOU,
+    ];
+    for signal in signals {
+        // SAFETY: `SigDfl` doesn't install a Rust function as signal handler.
+        unsafe { nix::sys::signal::signal(signal, SigHandler::SigDfl) }?;
+    }
+    Ok(())
+}
+
+/// Find the program that runs for the command `name`.
+///
+/// `Command` would search the `PATH` that the shell inherited, so we search
+/// the directories of the shell variable ourselves: after `unset PATH`,
+/// only builtins and commands with a `/` in their name can run.
+fn find_program(name: &str, path: Option<&str>) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+    path?
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(name))
+        .find(|program| is_executable(program))
+}
+
+/// Whether `path` is a file that we may run.
+/// `fs::metadata` follows symlinks, which many programs are.
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Convert an exit status to the number that `$?` shows.
+fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(signal) = status.signal() {
+        return signal_status(signal);
+    }
+    status.code().unwrap_or(1)
+}
+
+/// The status of a process that was killed by `signal`.
+///
+/// Like other shells, we report a process that was killed by a signal
+/// with 128 plus the signal number, e.g. 130 for `SIGINT`.
+fn signal_status(signal: i32) -> i32 {
+    128 + signal
+}
+
+/// What a file descriptor of a command is connected to.
+enum Io {
+    /// The stdin of the shell, usually the terminal.
+    Stdin,
+    /// The stdout of the shell, usually the terminal.
+    Stdout,
+    /// The stderr of the shell, usually the terminal.
+    Stderr,
+    /// Nothing: reads see the end of the file and writes are discarded.
+    Null,
+    /// A file opened by a redirection.
+    File(File),
+    /// The reading end of a pipe from the previous command.
+    PipeReader(PipeReader),
+    /// The writing end of a pipe to the next command.
+    PipeWriter(PipeWriter),
+}
+
+impl Io {
+    /// Create a pipe from which the command can read `input`.
+    /// This is used for here-documents and here-strings.
+    fn reading(input: String) -> Result<Self> {
+        let (reader, writer) = io::pipe()?;
+        Io::PipeWriter(writer).write_all(input.into_bytes())?;
+        Ok(Io::PipeReader(reader))
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            Io::Stdin => Io::Stdin,
+            Io::Stdout => Io::Stdout,
+            Io::Stderr => Io::Stderr,
+            Io::Null => Io::Null,
+            Io::File(file) => Io::File(file.try_clone()?),
+            Io::PipeReader(reader) => Io::PipeReader(reader.try_clone()?),
+            Io::PipeWriter(writer) => Io::PipeWriter(writer.try_clone()?),
+        })
+    }
+
+    /// Write `bytes`, which is how builtins produce output.
+    fn write_all(self, bytes: Vec<u8>) -> Result<()> {
+        match self {
+            Io::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            Io::Stderr => io::stderr().write_all(&bytes)?,
+            Io::File(mut file) => file.write_all(&bytes)?,
+            Io::PipeWriter(mut writer) => {
+                // Write from a separate thread, because the pipe only buffers
+                // a limited amount of data and nobody reads from it until the
+                // next command is started.
+                // Write errors are ignored: the reader may exit early, e.g. `head`.
+                thread::spawn(move || writer.write_all(&bytes));
+            }
+            Io::Stdin | Io::Null | Io::PipeReader(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl From<Io> for Stdio {
+    fn from(io: Io) -> Self {
+        match io {
+            Io::Stdin => Stdio::inherit(),
+            Io::Stdout => io::stdout().into(),
+            Io::Stderr => io::stderr().into(),
+            Io::Null => Stdio::null(),
+            Io::File(file) => file.into(),
+            Io::PipeReader(reader) => reader.into(),
+            Io::PipeWriter(writer) => writer.into(),
+        }
+    }
+}
+
+impl Element {
+    fn parse_operator(token: &str) -> Option<Self> {
+        match token {
+            "|" => Some(Self::Pipe),
+            "&&" => Some(Self::And),
+            "||" => Some(Self::Or),
+            "&" => Some(Self::Background),
+            _ => None,
+        }
+    }
+
+    /// The token of an operator, for error messages.
+    fn operator(&self) -> &'static str {
+        match self {
+            Self::Pipe => "|",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::Background => "&",
+            Self::Cmd(_) => "",
+        }
+    }
+}
+
+/// A chain is shown the way it could be typed, like `make && ./run | tee log`,
+/// which `jobs` uses for background jobs. The quotes and spaces may differ from
+/// what was typed, but reading the text again results in the same chain.
+impl std::fmt::Display for Chain {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, e) in self.elements.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match e {
+                Element::Cmd(cmd) => write!(f, "{cmd}")?,
+                e => write!(f, "{}", e.operator())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Cmd {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|a| format!("{}={}", a.name, a.value));
+        let words = self.words.iter().map(Word::to_string);
+        let redirects = self.redirects.iter().map(Redirect::to_string);
+        let all: Vec<String> = assignments.chain(words).chain(redirects).collect();
+        write!(f, "{}", all.join(" "))
+    }
+}
+
+impl std::fmt::Display for Redirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let fd = |default: u32| match self.fd {
+            fd if fd == default => String::new(),
+            fd => fd.to_string(),
+        };
+        match &self.target {
+            RedirectTarget::Read(path) => write!(f, "{}< {path}", fd(0)),
+            RedirectTarget::Write(path) => write!(f, "{}> {path}", fd(1)),
+            RedirectTarget::Append(path) => write!(f, "{}>> {path}", fd(1)),
+            RedirectTarget::Dup(target) if self.fd == 0 => write!(f, "<&{target}"),
+            RedirectTarget::Dup(target) => write!(f, "{}>&{target}", fd(1)),
+            // The body of a here-document is on the following lines, so we only show the operator.
+            RedirectTarget::HereDoc(_) => write!(f, "{}<<EOF", fd(0)),
+            RedirectTarget::HereString(word) => write!(f, "{}<<< {word}", fd(0)),
+        }
+    }
+}
+
+impl std::fmt::Display for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        Self::write_parts(f, &self.0, false)
+    }
+}
+
+impl Word {
+    /// Write the parts of a word, escaping what is special inside double quotes if `in_quotes`.
+    fn write_parts(
+        f: &mut std::fmt::Formatter,
+        parts: &[WordPart],
+        in_quotes: bool,
+    ) -> std::fmt::Result {
+        for (i, part) in parts.iter().enumerate() {
+            match part {
+                WordPart::Literal(text) | WordPart::Quoted(text) if in_quotes => {
+                    for c in text.chars() {
+                        if matches!(c, '"' | '\\' | '$' | '`') {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{c}")?;
+                    }
+                }
+                WordPart::Literal(text) => write!(f, "{text}")?,
+                // A single escaped character like in `a\ b`, unless it's a newline,
+                // because a backslash and a newline are removed entirely.
+                WordPart::Quoted(text) if text.chars().count() == 1 && text != "\n" => {
+                    write!(f, "\\{text}")?
+                }
+                WordPart::Quoted(text) => write!(f, "'{}'", text.replace('\'', "'\\''"))?,
+                WordPart::DoubleQuoted(parts) => {
+                    write!(f, "\"")?;
+                    Self::write_parts(f, parts, true)?;
+                    write!(f, "\"")?;
+                }
+                // `${x}y` needs braces, so that it isn't read as `$xy`, and so does `${10}`.
+                WordPart::Param(Param {
+                    name,
+                    op: ParamOp::Value,
+                }) if name.len() > 1 && name.chars().all(|c| c.is_ascii_digit())
+                    || matches!(parts.get(i + 1), Some(WordPart::Literal(next))
+                        if next.starts_with(|c: char| c == '_' || c.is_ascii_alphanumeric())) =>
+                {
+                    write!(f, "${{{name}}}")?
+                }
+                WordPart::Param(param) => write!(f, "{param}")?,
+                WordPart::Tilde(user) => write!(f, "~{user}")?,
+                WordPart::Arithmetic(expression) => write!(f, "$(({expression}))")?,
+                WordPart::CommandSub(chains) => {
+                    write!(f, "$(")?;
+                    for (i, chain) in chains.iter().enumerate() {
+                        // A chain that ends with `&` needs no `;` after it.
+                        match chains.get(i.wrapping_sub(1)) {
+                            None => {}
+                            Some(previous)
+                                if previous.elements.last() == Some(&Element::Background) =>
+                            {
+                                write!(f, " ")?
+                            }
+                            Some(_) => write!(f, "; ")?,
+                        }
+                        write!(f, "{chain}")?;
+                    }
+                    write!(f, ")")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = &self.name;
+        let colon = |colon: &bool| if *colon { ":" } else { "" };
+        match &self.op {
+            ParamOp::Value => write!(f, "${name}"),
+            ParamOp::Length => write!(f, "${{#{name}}}"),
+            ParamOp::Default { word, colon: c } => write!(f, "${{{name}{}-{word}}}", colon(c)),
+            ParamOp::Assign { word, colon: c } => write!(f, "${{{name}{}={word}}}", colon(c)),
+            ParamOp::Error { word, colon: c } => write!(f, "${{{name}{}?{word}}}", colon(c)),
+            ParamOp::Alternative { word, colon: c } => write!(f, "${{{name}{}+{word}}}", colon(c)),
+            ParamOp::RemovePrefix { pattern, longest } => {
+                let op = if *longest { "##" } else { "#" };
+                write!(f, "${{{name}{op}{pattern}}}")
+            }
+            ParamOp::RemoveSuffix { pattern, longest } => {
+                let op = if *longest { "%%" } else { "%" };
+                write!(f, "${{{name}{op}{pattern}}}")
+            }
+            ParamOp::Replace {
+                pattern,
+                replacement,
+                mode,
+            } => {
+                let op = match mode {
+                    ReplaceMode::First => "/",
+                    ReplaceMode::All => "//",
+                    ReplaceMode::Prefix => "/#",
+                    ReplaceMode::Suffix => "/%",
+                };
+                write!(f, "${{{name}{op}{pattern}/{replacement}}}")
+            }
+            ParamOp::Substring { offset, length } => {
+                // A space keeps `${x: -1}` from being read as `${x:-1}`.
+                let offset = offset.to_string();
+                let space = if offset.starts_with('-') { " " } else { "" };
+                write!(f, "${{{name}:{space}{offset}")?;
+                if let Some(length) = length {
+                    write!(f, ":{length}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl Cmd {
+    /// Start the command with the given stdin and stdout.
+    ///
+    /// Anything that is not redirected or piped is inherited from the shell.
+    /// That's how interactive programs like `vim` or `less` get access
+    /// to the terminal, and why stdout and stderr stay in order.
+    ///
+    /// Builtins run to completion right away, while external commands
+    /// are only spawned. Use [`Process::wait`] to wait for them.
+    ///
+    /// Also returns the command line after expansion, like `ls -l /home/me`.
+    /// An external command joins the process group `group`, if given.
+    fn run(
+        &self,
+        shell: &mut Shell,
+        stdin: Io,
+        stdout: Io,
+        group: Option<Pid>,
+    ) -> Result<(Process, String)> {
+        let mut args = vec![];
+        for word in &self.words {
+            args.extend(word.expand(shell)?);
+        }
+        let command_line = args.join(" ");
+        let [stdin, stdout, stderr] = self.redirect(shell, [stdin, stdout, Io::Stderr])?;
+
+        // Assignments are applied from left to right, so `A=1 B=$A` sets `B` to 1.
+        // We remember the previous values, in case they only apply to the command.
+        if let Some(assignment) = self
+            .assignments
+            .iter()
+            .find(|a| shell.readonly.contains(&a.name))
+        {
+            return Err(format!("{}: readonly variable", assignment.name).into());
+        }
+        let mut previous = vec![];
+        for assignment in &self.assignments {
+            let value = assignment.value.expand_to_string(shell)?;
+            let name = assignment.name.clone();
+            previous.push((name.clone(), shell.vars.insert(name, value)));
+        }
+        let Some((binary, args)) = args.split_first() else {
+            // Without a command, assignments set shell variables.
+            return Ok((Process::Finished(0), command_line));
+        };
+
+        // External commands get the exported variables and the assignments as
+        // environment variables, while builtins see the assignments as shell
+        // variables, like `HOME=/tmp cd`.
+        let mut env = shell.env();
+        env.extend(
+            previous
+                .iter()
+                .filter_map(|(name, _)| Some((name.clone(), shell.var(name)?.to_string()))),
+        );
+        let ios = [stdin, stdout, stderr];
+        let process = Self::run_binary(shell, binary, args, &env, ios, group);
+        for (name, value) in previous.into_iter().rev() {
+            match value {
+                Some(value) => shell.vars.insert(name, value),
+                None => shell.vars.remove(&name),
+            };
+        }
+        Ok((process?, command_line))
+    }
+
+    /// Run a builtin or an external command with already expanded arguments.
+    fn run_binary(
+        shell: &mut Shell,
+        binary: &str,
+        args: &[String],
+        env: &[(String, String)],
+        [stdin, stdout, stderr]: [Io; 3],
+        group: Option<Pid>,
+    ) -> Result<Process> {
+        let result = match binary {
+            "cd" => builtins::Cd::new(args.to_vec()).run(shell),
+            "exit" => {
+                let status = args.first().and_then(|s| s.parse().ok()).unwrap_or(0);
+                builtins::Exit::new(status).run(shell)
+            }
+            "history" => builtins::History::new().run(),
+            "dirs" => builtins::Dirs::new(args.to_vec()).run(shell),
+            "pushd" => builtins::Pushd::new(args.to_vec()).run(shell),
+            "popd" => builtins::Popd::new(args.to_vec()).run(shell),
+            "let" => builtins::Let::new(args.to_vec()).run(shell),
+            "shopt" => builtins::Shopt::new(args.to_vec()).run(&mut shell.options),
+            "export" => builtins::Export::new(args.to_vec()).run(shell),
+            "readonly" => builtins::Readonly::new(args.to_vec()).run(shell),
+            "unset" => builtins::Unset::new(args.to_vec()).run(shell),
+            "set" => builtins::Set::new(args.to_vec()).run(shell),
+            "jobs" => builtins::Jobs::new().run(shell),
+            "fg" => builtins::Fg::new(args.to_vec()).run(shell),
+            "bg" => builtins::Bg::new(args.to_vec()).run(shell),
+            // `%1` is a shorthand for `fg %1`.
+            _ if binary.starts_with('%') => builtins::Fg::new(vec![binary.to_string()]).run(shell),
+            _ => {
+                let ios = [stdin, stdout, stderr];
+                return Self::run_external(binary, shell.var("PATH"), args, env, ios, group);
+            }
+        };
+
+        let Some(output) = result? else {
+            return Ok(Process::Finished(0));
+        };
+        stderr.write_all(output.stderr)?;
+        stdout.write_all(output.stdout)?;
+        Ok(Process::Finished(exit_code(output.status)))
+    }
+
+    /// Apply the redirections of the command to its stdin, stdout and stderr.
+    ///
+    /// Redirections are applied from left to right, so `> file 2>&1`
+    /// sends both stdout and stderr to `file`, while `2>&1 > file` sends
+    /// stderr to where stdout pointed before.
+    fn redirect(&self, shell: &mut Shell, mut fds: [Io; 3]) -> Result<[Io; 3]> {
+        for redirect in &self.redirects {
+            let mut open = |path: &Word, options: &mut OpenOptions| {
+                let path = path.expand_to_string(shell)?;
+                Ok::<_, Box<dyn std::error::Error>>(
+                    options.open(&path).map_err(|e| format!("{path}: {e}"))?,
+                )
+            };
+            fds[redirect.fd as usize] = match &redirect.target {
+                RedirectTarget::Read(path) => Io::File(open(path, OpenOptions::new().read(true))?),
+                RedirectTarget::Write(path) => Io::File(open(
+                    path,
+                    OpenOptions::new().write(true).create(true).truncate(true),
+                )?),
+                RedirectTarget::Append(path) => {
+                    Io::File(open(path, OpenOptions::new().append(true).create(true))?)
+                }
+                RedirectTarget::Dup(target) => match fds.get(*target as usize) {
+                    Some(io) => io.try_clone()?,
+                    None => return Err(format!("{target}: unsupported file descriptor").into()),
+                },
+                RedirectTarget::HereDoc(body) => Io::reading(body.expand_to_string(shell)?)?,
+                RedirectTarget::HereString(word) => {
+                    Io::reading(format!("{}\n", word.expand_to_string(shell)?))?
+                }
+            };
+        }
+        Ok(fds)
+    }
+
+    /// Start an external command, which is looked up in the directories of
+    /// the shell variable `path`, unless its name contains a `/`.
+    fn run_external(
+        binary: &str,
+        path: Option<&str>,
+        args: &[String],
+        env: &[(String, String)],
+        [stdin, stdout, stderr]: [Io; 3],
+        group: Option<Pid>,
+    ) -> Result<Process> {
+        // Like bash, use 127 if the command doesn't exist and 126 if it can't run.
+        let Some(program) = find_program(binary, path) else {
+            eprintln!("Error: {binary}: command not found");
+            return Ok(Process::Finished(127));
+        };
+        let mut command = Command::new(program);
+        // The child only gets the environment we pass, not the one the shell inherited.
+        // It still sees the name it was called by, not the full path.
+        command
+            .arg0(binary)
+            .args(args)
+            .env_clear()
+            .envs(env.iter().cloned())
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr);
+        if let Some(pgid) = group {
+            // The child joins the group itself before `exec`, so it's in the group
+            // before we hand the terminal to the group.
+            command.process_group(pgid.as_raw());
+        }
+        // SAFETY: `reset_signals` only calls `signal`, which is safe to call
+        // between `fork` and `exec`.
+        unsafe { command.pre_exec(reset_signals) };
+        match command.spawn() {
+            Ok(child) => Ok(Process::Child {
+                pid: Pid::from_raw(child.id() as i32),
+                stopped: false,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("Error: {binary}: command not found");
+                Ok(Process::Finished(127))
+            }
+            Err(e) => {
+                eprintln!("Error: {binary}: {e}");
+                Ok(Process::Finished(126))
+            }
+        }
+    }
+}
+
+/// A field produced by expanding a word.
+#[derive(Default)]
+struct Field {
+    text: String,
+    /// The field as a pattern for pathname expansion,
+    /// in which quoted characters are escaped so they match literally.
+    pattern: String,
+    /// Whether any part of the field was quoted.
+    /// Quoted fields are kept even if they are empty, like `""`.
+    quoted: bool,
+    /// Whether the field was ended by an `IFS` character that is not
+    /// whitespace. Such fields are kept even if they are empty, like the
+    /// middle field of `a::b` with `IFS=:`.
+    delimited: bool,
+}
+
+/// The fields of a word that is being expanded.
+struct Expansion {
+    /// The fields so far. Expansions append to the last one.
+    fields: Vec<Field>,
+    /// The characters that split the results of expansions into fields,
+    /// from the `IFS` variable. This is `None` where only one value makes
+    /// sense, like in assignments.
+    ifs: Option<String>,
+    /// Whether we are inside of double quotes, where nothing is split
+    /// and wildcards like `*` match literally.
+    in_quotes: bool,
+}
+
+impl Expansion {
+    fn new(ifs: Option<&str>) -> Self {
+        Self {
+            fields: vec![Field::default()],
+            ifs: ifs.map(String::from),
+            in_quotes: false,
+        }
+    }
+
+    fn current(&mut self) -> &mut Field {
+        self.fields
+            .last_mut()
+            .expect("an expansion has at least one field")
+    }
+
+    /// Append `text` to the current field.
+    fn push_str(&mut self, text: &str) {
+        if self.in_quotes {
+            return self.push_quoted(text);
+        }
+        let field = self.current();
+        field.text.push_str(text);
+        field.pattern.push_str(text);
+    }
+
+    /// Append quoted `text` to the current field, in which wildcards match literally.
+    fn push_quoted(&mut self, text: &str) {
+        let field = self.current();
+        field.text.push_str(text);
+        field.pattern.push_str(&pattern::escape(text));
+        field.quoted = true;
+    }
+
+    /// Append the result of an expansion like `$x` or `$(ls)` to the current field.
+    ///
+    /// Outside of double quotes, the characters of `IFS` in the result
+    /// separate fields: with the default `IFS` of space, tab and newline,
+    /// `x$(echo a b)y` expands to the two fields `xa` and `by`.
+    ///
+    /// Like in POSIX shells, a run of `IFS` whitespace counts as a single
+    /// separator, while every other `IFS` character separates fields on its
+    /// own: with `IFS=:`, `a::b` is split into `a`, an empty field and `b`.
+    fn push_split(&mut self, text: &str) {
+        let Some(ifs) = self.ifs.clone().filter(|_| !self.in_quotes) else {
+            return self.push_str(text);
+        };
+        let is_space = |c: char| ifs.contains(c) && matches!(c, ' ' | '\t' | '\n');
+        let mut chars = text.chars().peekable();
+        let mut piece = String::new();
+        while let Some(c) = chars.next() {
+            if !ifs.contains(c) {
+                piece.push(c);
+                continue;
+            }
+            // A separator is `IFS` whitespace around at most one other `IFS` character.
+            let mut delimited = !is_space(c);
+            while let Some(&next) = chars.peek() {
+                if is_space(next) || (ifs.contains(next) && !delimited) {
+                    delimited |= !is_space(next);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            self.push_str(&piece);
+            piece.clear();
+            self.current().delimited = delimited;
+            self.fields.push(Field::default());
+        }
+        self.push_str(&piece);
+    }
+
+    /// Start a new field, as `"$@"` does for every positional parameter.
+    fn push_field(&mut self) {
+        self.fields.push(Field {
+            quoted: self.in_quotes,
+            ..Field::default()
+        });
+    }
+
+    /// The resulting fields, with pathname expansion applied.
+    ///
+    /// Empty fields disappear, unless they were quoted or delimited. A field with
+    /// unquoted wildcards is replaced by the paths that match it. If no path
+    /// matches, the field is kept as it is, unless the `nullglob` or
+    /// `failglob` option is set.
+    fn into_fields(self, options: &Options) -> Result<Vec<String>> {
+        let mut fields = vec![];
+        for field in self.fields {
+            if field.text.is_empty() && !field.quoted && !field.delimited {
+                continue;
+            }
+            if !pattern::has_wildcards(&field.pattern) {
+                fields.push(field.text);
+                continue;
+            }
+            let paths = glob::expand(&field.pattern);
+            if !paths.is_empty() {
+                fields.extend(paths);
+            } else if options.failglob {
+                return Err(format!("no match: {}", field.text).into());
+            } else if !options.nullglob {
+                fields.push(field.text);
+            }
+        }
+        Ok(fields)
+    }
+
+    /// The resulting fields joined by spaces.
+    fn into_string(self) -> String {
+        let fields: Vec<String> = self.fields.into_iter().map(|field| field.text).collect();
+        fields.join(" ")
+    }
+
+    /// The resulting fields as a pattern, joined by spaces.
+    fn into_pattern(self) -> String {
+        let fields: Vec<String> = self.fields.into_iter().map(|field| field.pattern).collect();
+        fields.join(" ")
+    }
+}
+
+impl Word {
+    /// Append a part, merging it with the previous part if both are literals.
+    fn push(&mut self, part: WordPart) {
+        match (self.0.last_mut(), part) {
+            (Some(WordPart::Literal(last)), WordPart::Literal(text))
+            | (Some(WordPart::Quoted(last)), WordPart::Quoted(text)) => last.push_str(&text),
+            (_, part) => self.0.push(part),
+        }
+    }
+
+    /// The text of the word with quotes removed, if it contains no expansions.
+    fn literal(&self) -> Option<String> {
+        let mut text = String::new();
+        for part in &self.0 {
+            match part {
+                WordPart::Literal(s) | WordPart::Quoted(s) => text.push_str(s),
+                WordPart::DoubleQuoted(parts) => text.push_str(&Word(parts.clone()).literal()?),
+                WordPart::Param(_)
+                | WordPart::Tilde(_)
+                | WordPart::Arithmetic(_)
+                | WordPart::CommandSub(_) => return None,
+            }
+        }
+        Some(text)
+    }
+
+    /// Whether any part of the word is quoted or escaped.
+    fn is_quoted(&self) -> bool {
+        self.0
+            .iter()
+            .any(|part| !matches!(part, WordPart::Literal(_) | WordPart::Tilde(_)))
+    }
+
+    /// Parse the word as an assignment, if it starts with an unquoted `NAME=`.
+    fn as_assignment(&self) -> Option<Assignment> {
+        let Some(WordPart::Literal(first)) = self.0.first() else {
+            return None;
+        };
+        let (name, value) = first.split_once('=')?;
+        if !is_valid_name(name) {
+            return None;
+        }
+        let mut parts = self.0.clone();
+        if value.is_empty() {
+            parts.remove(0);
+        } else {
+            parts[0] = WordPart::Literal(value.to_string());
+        }
+        Some(Assignment {
+            name: name.to_string(),
+            value: Word(parts).mark_tildes(true),
+        })
+    }
+
+    /// Turn unquoted tilde prefixes like `~` or `~user` into [`WordPart::Tilde`].
+    ///
+    /// A tilde prefix starts with a `~` at the start of the word and goes up
+    /// to the first `/`. In assignments like `PATH=~/bin:~/.cargo/bin`,
+    /// a `~` after a `:` starts a tilde prefix too, and a `:` also ends it.
+    fn mark_tildes(self, assignment: bool) -> Word {
+        let is_delimiter = |c: char| c == '/' || (assignment && c == ':');
+        let count = self.0.len();
+        let mut word = Word::default();
+        for (i, part) in self.0.into_iter().enumerate() {
+            let WordPart::Literal(text) = part else {
+                word.push(part);
+                continue;
+            };
+            let mut rest = text.as_str();
+            let mut can_start = i == 0;
+            while !rest.is_empty() {
+                if can_start && rest.starts_with('~') {
+                    let end = rest.find(is_delimiter);
+                    // The prefix must not be quoted, so it has to end
+                    // inside of this literal or at the end of the word.
+                    if end.is_some() || i == count - 1 {
+                        let end = end.unwrap_or(rest.len());
+                        word.push(WordPart::Tilde(rest[1..end].to_string()));
+                        rest = &rest[end..];
+                    }
+                }
+                let end = match rest.find(':') {
+                    Some(colon) if assignment => colon + 1,
+                    _ => rest.len(),
+                };
+                if end > 0 {
+                    word.push(WordPart::Literal(rest[..end].to_string()));
+                }
+                rest = &rest[end..];
+                can_start = assignment;
+            }
+        }
+        word
+    }
+
+    /// Expand the word into the arguments that are passed to a command.
+    ///
+    /// An unquoted word that expands to nothing, like `$UNSET`,
+    /// disappears instead of becoming an empty argument.
+    /// The output of an unquoted command substitution is split
+    /// into multiple arguments at whitespace, and unquoted patterns
+    /// like `*.rs` are replaced by the matching paths.
+    ///
+    /// Before anything else, brace expansion turns a word like `a{b,c}`
+    /// into multiple words, which are then expanded one after the other.
+    fn expand(&self, shell: &mut Shell) -> Result<Vec<String>> {
+        let mut fields = vec![];
+        for word in self.expand_braces() {
+            let mut expansion = Expansion::new(Some(shell.ifs()));
+            for part in &word.0 {
+                Self::expand_part(part, shell, &mut expansion)?;
+            }
+            fields.extend(expansion.into_fields(&shell.options)?);
+        }
+        Ok(fields)
+    }
+
+    /// Apply brace expansion: `file.{rs,txt}` becomes `file.rs` and `file.txt`.
+    /// See [`braces`] for the details.
+    fn expand_braces(&self) -> Vec<Word> {
+        let has_braces = self.0.iter().any(|part| match part {
+            WordPart::Literal(text) => text.contains('{'),
+            _ => false,
+        });
+        if !has_braces {
+            return vec![self.clone()];
+        }
+        // Only unquoted braces and commas count, so we split literals into
+        // single characters and keep all other parts as they are.
+        let mut parts = vec![];
+        for part in &self.0 {
+            match part {
+                WordPart::Literal(text) => {
+                    parts.extend(text.chars().map(|c| WordPart::Literal(c.to_string())))
+                }
+                part => parts.push(part.clone()),
+            }
+        }
+        braces::expand(&parts)
+            .into_iter()
+            .map(|parts| {
+                let mut word = Word::default();
+                for part in parts {
+                    word.push(part);
+                }
+                word
+            })
+            .collect()
+    }
+
+    /// Expand the word into a single string.
+    /// This is used where only one value makes sense, like in assignments.
+    fn expand_to_string(&self, shell: &mut Shell) -> Result<String> {
+        let mut expansion = Expansion::new(None);
+        for part in &self.0 {
+            Self::expand_part(part, shell, &mut expansion)?;
+        }
+        Ok(expansion.into_string())
+    }
+
+    fn expand_part(part: &WordPart, shell: &mut Shell, expansion: &mut Expansion) -> Result<()> {
+        match part {
+            WordPart::Literal(text) => expansion.push_str(text),
+            WordPart::Quoted(text) => expansion.push_quoted(text),
+            WordPart::DoubleQuoted(parts) => {
+                expansion.current().quoted = true;
+                let in_quotes = std::mem::replace(&mut expansion.in_quotes, true);
+                for part in parts {
+                    Self::expand_part(part, shell, expansion)?;
+                }
+                expansion.in_quotes = in_quotes;
+            }
+            WordPart::Param(param) => param.expand(shell, expansion)?,
+            // The home directory is not split or matched as a pattern,
+            // even if it contains spaces or wildcards.
+            WordPart::Tilde(user) => match shell.home_dir(user) {
+                Some(dir) => expansion.push_quoted(&dir),
+                None => expansion.push_str(&format!("~{user}")),
+            },
+            WordPart::Arithmetic(expression) => {
+                let expression = expression.expand_to_string(shell)?;
+                let value = arith::eval(&expression, shell)?;
+                expansion.push_split(&value.to_string());
+            }
+            WordPart::CommandSub(chains) => {
+                let output = Chain::capture(chains, shell)?;
+                expansion.push_split(&output);
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the word into a shell pattern, as used by `${name#pattern}`.
+    ///
+    /// Quoted characters match literally, so they are escaped with
+    /// a backslash: `"*".txt` only matches the file `*.txt`.
+    fn expand_to_pattern(&self, shell: &mut Shell) -> Result<String> {
+        let mut expansion = Expansion::new(None);
+        for part in &self.0 {
+            Self::expand_part(part, shell, &mut expansion)?;
+        }
+        Ok(expansion.into_pattern())
+    }
+}
+
+impl Param {
+    /// Expand the word of `${x:-word}` or `${x:+word}` and append it to `expansion`.
+    ///
+    /// The word is the result of an expansion, so unquoted text in it is split
+    /// into fields: `${x:-a b}` is `a` and `b`, but `${x:-"a b"}` is one field.
+    fn expand_word(word: &Word, shell: &mut Shell, expansion: &mut Expansion) -> Result<()> {
+        for part in &word.0 {
+            match part {
+                WordPart::Literal(text) => expansion.push_split(text),
+                part => Word::expand_part(part, shell, expansion)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the parameter and append the result to `expansion`.
+    fn expand(&self, shell: &mut Shell, expansion: &mut Expansion) -> Result<()> {
+        let value = shell.param(&self.name);
+        let is_unset = |colon: bool| match &value {
+            None => true,
+            Some(value) => colon && value.is_empty(),
+        };
+        let value_or_empty = || value.clone().unwrap_or_default();
+        match &self.op {
+            // `"$@"` expands to one field per positional parameter,
+            // and so do `$@` and `$*` without quotes, before they are split.
+            ParamOp::Value if self.name == "@" || (self.name == "*" && !expansion.in_quotes) => {
+                if shell.positional.is_empty() && expansion.current().text.is_empty() {
+                    // `"$@"` without positional parameters is no field at all, not `""`.
+                    expansion.current().quoted = false;
+                }
+                for (i, arg) in shell.positional.iter().enumerate() {
+                    if i > 0 {
+                        expansion.push_field();
+                    }
+                    expansion.push_split(arg);
+                }
+            }
+            ParamOp::Value => expansion.push_split(&value_or_empty()),
+            ParamOp::Length => expansion.push_split(&value_or_empty().chars().count().to_string()),
+            ParamOp::Default { word, colon } => {
+                if is_unset(*colon) {
+                    Self::expand_word(word, shell, expansion)?;
+                } else {
+                    expansion.push_split(&value_or_empty());
+                }
+            }
+            ParamOp::Assign { word, colon } => {
+                if is_unset(*colon) {
+                    if !is_valid_name(&self.name) {
+                        return Err(format!("${}: cannot assign in this way", self.name).into());
+                    }
+                    let value = word.expand_to_string(shell)?;
+                    expansion.push_split(&value);
+                    shell.assign(&self.name, value)?;
+                } else {
+                    expansion.push_split(&value_or_empty());
+                }
+            }
+            ParamOp::Error { word, colon } => {
+                if is_unset(*colon) {
+                    let mut message = word.expand_to_string(shell)?;
+                    if message.is_empty() {
+                        message = "parameter null or not set".to_string();
+                    }
+                    return Err(format!("{}: {message}", self.name).into());
+                }
+                expansion.push_split(&value_or_empty());
+            }
+            ParamOp::Alternative { word, colon } => {
+                if !is_unset(*colon) {
+                    Self::expand_word(word, shell, expansion)?;
+                }
+            }
+            ParamOp::RemovePrefix { pattern, longest } => {
+                let pattern = pattern.expand_to_pattern(shell)?;
+                let value = value_or_empty();
+                expansion.push_split(pattern::remove_prefix(&value, &pattern, *longest));
+            }
+            ParamOp::RemoveSuffix { pattern, longest } => {
+                let pattern = pattern.expand_to_pattern(shell)?;
+                let value = value_or_empty();
+                expansion.push_split(pattern::remove_suffix(&value, &pattern, *longest));
+            }
+            ParamOp::Replace {
+                pattern,
+                replacement,
+                mode,
+            } => {
+                let pattern = pattern.expand_to_pattern(shell)?;
+                let replacement = replacement.expand_to_string(shell)?;
+                expansion.push_split(&pattern::replace(
+                    &value_or_empty(),
+                    &pattern,
+                    &replacement,
+                    *mode,
+                ));
+            }
+            ParamOp::Substring { offset, length } => {
+                let value: Vec<char> = value_or_empty().chars().collect();
+                let len = value.len() as i64;
+                // The offset and length are arithmetic expressions, like `${s:i+1}`.
+                let parse = |word: &Word, shell: &mut Shell| -> Result<i64> {
+                    let expression = word.expand_to_string(shell)?;
+                    arith::eval(&expression, shell)
+                };
+                // Negative numbers count from the end of the value.
+                // Huge numbers saturate instead of overflowing, like `${s:1:2**63-1}`.
+                let mut start = parse(offset, shell)?;
+                if start < 0 {
+                    start = start.saturating_add(len);
+                }
+                let start = start.clamp(0, len);
+                let end = match length {
+                    None => len,
+                    Some(length) => match parse(length, shell)? {
+                        length if length < 0 => length.saturating_add(len),
+                        length => start.saturating_add(length),
+                    },
+                };
+                if end < start {
+                    return Err(format!("{}: substring expression < 0", self.name).into());
+                }
+                let end = end.min(len);
+                let substring: String = value[start as usize..end as usize].iter().collect();
+                expansion.push_split(&substring);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shell patterns like `*.txt`, as used by `${name#pattern}`.
+///
+/// `*` matches any string, `?` matches any character and `[a-z]` matches
+/// one of the characters in the brackets, or none of them with `[!a-z]`.
+/// A backslash makes the next character match literally.
+mod pattern {
+    use crate::ReplaceMode;
+
+    /// Escape `text` so that all of its characters match literally.
+    pub fn escape(text: &str) -> String {
+        let mut escaped = String::new();
+        for c in text.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// Remove the backslashes from an escaped pattern.
+    pub fn unescape(pattern: &str) -> String {
+        let mut text = String::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => text.extend(chars.next()),
+                c => text.push(c),
+            }
+        }
+        text
+    }
+
+    /// Whether `pattern` contains unescaped wildcards like `*`,
+    /// so it can match something else than itself.
+    pub fn has_wildcards(pattern: &str) -> bool {
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '*' | '?' | '[' => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Whether `text` matches `pattern` as a whole.
+    pub fn matches(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        let (mut p, mut t) = (0, 0);
+        // Where to continue if matching fails after a `*`:
+        // the pattern after the `*` and the text it should match next.
+        let mut backtrack = None;
+        while t < text.len() {
+            if pattern.get(p) == Some(&'*') {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            if p < pattern.len() {
+                let (len, matched) = match_char(&pattern[p..], text[t]);
+                if matched {
+                    p += len;
+                    t += 1;
+                    continue;
+                }
+            }
+            // Let the last `*` match one more character and try again.
+            match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, t));
+                }
+                None => return false,
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+
+    /// Match `c` against the start of `pattern`, which is not a `*`.
+    /// Returns how many characters of the pattern were used and whether it matched.
+    fn match_char(pattern: &[char], c: char) -> (usize, bool) {
+        match pattern[0] {
+            '?' => (1, true),
+            '\\' if pattern.len() > 1 => (2, pattern[1] == c),
+            '[' => match_bracket(pattern, c).unwrap_or((1, c == '[')),
+            p => (1, p == c),
+        }
+    }
+
+    /// Match `c` against a bracket expression like `[a-z]`.
+    /// Returns `None` if the bracket is not closed, so it's taken literally.
+    fn match_bracket(pattern: &[char], c: char) -> Option<(usize, bool)> {
+        let mut i = 1;
+        let negate = matches!(pattern.get(i), Some('!' | '^'));
+        if negate {
+            i += 1;
+        }
+        let mut matched = false;
+        let mut first = true;
+        loop {
+            let mut lo = *pattern.get(i)?;
+            // A `]` right after the opening bracket is part of the set.
+            if lo == ']' && !first {
+                return Some((i + 1, matched != negate));
+            }
+            first = false;
+            i += 1;
+            if lo == '\\' {
+                lo = *pattern.get(i)?;
+                i += 1;
+            }
+            if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|&hi| hi != ']') {
+                let hi = pattern[i + 1];
+                matched |= lo <= c && c <= hi;
+                i += 2;
+            } else {
+                matched |= lo == c;
+            }
+        }
+    }
+
+    /// The byte offsets of all character boundaries in `text`, including its end.
+    fn boundaries(text: &str) -> Vec<usize> {
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect()
+    }
+
+    /// Remove the shortest or longest prefix of `text` that matches `pattern`.
+    pub fn remove_prefix<'a>(text: &'a str, pattern: &str, longest: bool) -> &'a str {
+        let mut ends = boundaries(text);
+        if longest {
+            ends.reverse();
+        }
+        match ends.into_iter().find(|&end| matches(pattern, &text[..end])) {
+            Some(end) => &text[end..],
+            None => text,
+        }
+    }
+
+    /// Remove the shortest or longest suffix of `text` that matches `pattern`.
+    pub fn remove_suffix<'a>(text: &'a str, pattern: &str, longest: bool) -> &'a str {
+        let mut starts = boundaries(text);
+        if !longest {
+            starts.reverse();
+        }
+        match starts
+            .into_iter()
+            .find(|&start| matches(pattern, &text[start..]))
+        {
+            Some(start) => &text[..start],
+            None => text,
+        }
+    }
+
+    /// Replace the longest matches of `pattern` in `text` with `replacement`.
+    pub fn replace(text: &str, pattern: &str, replacement: &str, mode: ReplaceMode) -> String {
+        let bounds = boundaries(text);
+        let mut result = String::new();
+        let mut i = 0;
+        while i + 1 < bounds.len() {
+            let start = bounds[i];
+            let can_start = mode != ReplaceMode::Prefix || i == 0;
+            let end = (i + 1..bounds.len()).rev().find(|&j| {
+                can_start
+                    && (mode != ReplaceMode::Suffix || j == bounds.len() - 1)
+                    && matches(pattern, &text[start..bounds[j]])
+            });
+            match end {
+                Some(j) => {
+                    result.push_str(replacement);
+                    i = j;
+                    if mode != ReplaceMode::All {
+                        result.push_str(&text[bounds[j]..]);
+                        return result;
+                    }
+                }
+                None => {
+                    result.push_str(&text[start..bounds[i + 1]]);
+                    i += 1;
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Integer arithmetic, as used by `$(( ... ))`, `(( ... ))` and `let`.
+///
+/// Expressions work like in C, with 64-bit integers that wrap around on overflow.
+/// From the highest to the lowest precedence, the operators are:
+///
+/// - `i++` `i--`, `++i` `--i`, unary `-` `+` `!` `~`
+/// - `**` (power), `*` `/` `%`, `+` `-`, `<<` `>>`
+/// - `<` `<=` `>` `>=`, `==` `!=`, `&`, `^`, `|`, `&&`, `||`
+/// - `cond ? a : b`, assignments like `=` and `+=`, and `,`
+///
+/// Variables can be used by name, like `i + 1`. An unset or empty variable
+/// is 0, and a variable that contains an expression is evaluated, too.
+/// Numbers can be written in hex as `0x1f`, in octal as `017`,
+/// or in any base up to 36 as `base#digits`, like `2#1010`.
+mod arith {
+    use crate::{is_valid_name, Result, Shell};
+
+    /// How deeply variables can refer to other variables, like `a=b b=a`.
+    const MAX_DEPTH: usize = 64;
+
+    /// The binary operators from the lowest to the highest precedence,
+    /// except for `**`, which is right-associative.
+    const LEVELS: &[&[&str]] = &[
+        &["||"],
+        &["&&"],
+        &["|"],
+        &["^"],
+        &["&"],
+        &["==", "!="],
+        &["<=", ">=", "<", ">"],
+        &["<<", ">>"],
+        &["+", "-"],
+        &["*", "/", "%"],
+    ];
+
+    /// All operators, longer ones first so that `<<=` isn't read as `<` and `<=`.
+    const OPERATORS: &[&str] = &[
+        "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+        "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&",
+        "^", "|", "?", ":", ",", "(", ")",
+    ];
+
+    #[derive(Clone, PartialEq, Debug)]
+    enum Token {
+        Number(i64),
+        Name(String),
+        Operator(&'static str),
+    }
+
+    /// Evaluate an arithmetic expression. Assignments change shell variables.
+    pub fn eval(expression: &str, shell: &mut Shell) -> Result<i64> {
+        eval_nested(expression, shell, 0)
+    }
+
+    fn eval_nested(expression: &str, shell: &mut Shell, depth: usize) -> Result<i64> {
+        let tokens = tokenize(expression)?;
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+        let mut evaluator = Evaluator {
+            tokens,
+            current: 0,
+            shell,
+            skip: false,
+            depth,
+        };
+        let value = evaluator.comma()?;
+        match evaluator.tokens.get(evaluator.current) {
+            None => Ok(value),
+            Some(_) => Err(format!("{}: syntax error in expression", expression.trim()).into()),
+        }
+    }
+
+    fn tokenize(expression: &str) -> Result<Vec<Token>> {
+        let mut tokens = vec![];
+        let mut rest = expression.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let len = if c.is_ascii_alphanumeric() || c == '_' {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '#')))
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+                tokens.push(if c.is_ascii_digit() {
+                    Token::Number(parse_number(word)?)
+                } else if is_valid_name(word) {
+                    Token::Name(word.to_string())
+                } else {
+                    return Err(format!("{word}: syntax error in expression").into());
+                });
+                len
+            } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                tokens.push(Token::Operator(op));
+                op.len()
+            } else {
+                return Err(format!("{rest}: syntax error: invalid arithmetic operator").into());
+            };
+            rest = rest[len..].trim_start();
+        }
+        Ok(tokens)
+    }
+
+    /// Parse a number like `42`, `0x2a`, `052` or `16#2a`.
+    fn parse_number(text: &str) -> Result<i64> {
+        let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+            (base.parse().unwrap_or(0), digits)
+        } else if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            (16, hex)
+        } else if text.len() > 1 && text.starts_with('0') {
+            (8, &text[1..])
+        } else {
+            (10, text)
+        };
+        if !(2..=36).contains(&base) {
+            return Err(format!("{text}: invalid arithmetic base").into());
+        }
+        i64::from_str_radix(digits, base)
+            .map_err(|_| format!("{text}: value too great for base").into())
+    }
+
+    /// A recursive descent parser that evaluates the expression while parsing it.
+    struct Evaluator<'a> {
+        tokens: Vec<Token>,
+        current: usize,
+        shell: &'a mut Shell,
+        /// Whether we are in a part of the expression that is not evaluated,
+        /// like the right side of `0 && (i = 1)`. It's parsed,
+        /// but doesn't assign variables or fail on division by zero.
+        skip: bool,
+        depth: usize,
+    }
+
+    impl Evaluator<'_> {
+        fn peek(&self) -> Option<&Token> {
+            self.tokens.get(self.current)
+        }
+
+        /// Consume the operator `op` if it comes next.
+        fn eat(&mut self, op: &str) -> bool {
+            let found = matches!(self.peek(), Some(Token::Operator(next)) if *next == op);
+            if found {
+                self.current += 1;
+            }
+            found
+        }
+
+        fn expect(&mut self, op: &str) -> Result<()> {
+            if self.eat(op) {
+                Ok(())
+            } else {
+                Err(self.syntax_error())
+            }
+        }
+
+        fn syntax_error(&self) -> Box<dyn std::error::Error> {
+            match self.peek() {
+                Some(token) => format!("syntax error in expression near {token:?}").into(),
+                None => "syntax error: operand expected".into(),
+            }
+        }
+
+        /// `a, b`: evaluates both and results in `b`.
+        fn comma(&mut self) -> Result<i64> {
+            let mut value = self.assignment()?;
+            while self.eat(",") {
+                value = self.assignment()?;
+            }
+            Ok(value)
+        }
+
+        /// `name = value` or a compound assignment like `name += value`.
+        fn assignment(&mut self) -> Result<i64> {
+            if let (Some(Token::Name(name)), Some(Token::Operator(op))) =
+                (self.peek(), self.tokens.get(self.current + 1))
+            {
+                let is_assignment = op.ends_with('=') && !matches!(*op, "==" | "!=" | "<=" | ">=");
+                if is_assignment {
+                    let (name, op) = (name.clone(), *op);
+                    self.current += 2;
+                    let mut value = self.assignment()?;
+                    if op != "=" {
+                        let current = self.var(&name)?;
+                        value = self.apply(&op[..op.len() - 1], current, value)?;
+                    }
+                    return self.set_var(&name, value);
+                }
+            }
+            self.conditional()
+        }
+
+        /// `cond ? a : b`, which only evaluates one of `a` and `b`.
+        fn conditional(&mut self) -> Result<i64> {
+            let condition = self.binary(0)?;
+            if !self.eat("?") {
+                return Ok(condition);
+            }
+            let skip = self.skip;
+            self.skip = skip || condition == 0;
+            let a = self.assignment()?;
+            self.expect(":")?;
+            self.skip = skip || condition != 0;
+            let b = self.conditional()?;
+            self.skip = skip;
+            Ok(if condition != 0 { a } else { b })
+        }
+
+        /// Binary operators with the precedence of `LEVELS[level]` or higher.
+        fn binary(&mut self, level: usize) -> Result<i64> {
+            let Some(ops) = LEVELS.get(level) else {
+                return self.power();
+            };
+            let mut left = self.binary(level + 1)?;
+            while let Some(&op) = ops
+                .iter()
+                .find(|op| matches!(self.peek(), Some(Token::Operator(next)) if next == *op))
+            {
+                self.current += 1;
+                // `&&` and `||` don't evaluate the right side if the left side decides.
+                let skip = self.skip;
+                self.skip |= (op == "&&" && left == 0) || (op == "||" && left != 0);
+                let right = self.binary(level + 1)?;
+                self.skip = skip;
+                left = self.apply(op, left, right)?;
+            }
+            Ok(left)
+        }
+
+        /// `a ** b`, which groups from the right: `2 ** 3 ** 2` is `2 ** 9`.
+        fn power(&mut self) -> Result<i64> {
+            let base = self.unary()?;
+            if self.eat("**") {
+                let exponent = self.power()?;
+                return self.apply("**", base, exponent);
+            }
+            Ok(base)
+        }
+
+        fn unary(&mut self) -> Result<i64> {
+            for op in ["++", "--"] {
+                if self.eat(op) {
+                    let Some(Token::Name(name)) = self.peek().cloned() else {
+                        return Err(self.syntax_error());
+                    };
+                    self.current += 1;
+                    let value = self.var(&name)?;
+                    let value = if op == "++" {
+                        value.wrapping_add(1)
+                    } else {
+                        value.wrapping_sub(1)
+                    };
+                    return self.set_var(&name, value);
+                }
+            }
+            if self.eat("-") {
+                return Ok(self.unary()?.wrapping_neg());
+            }
+            if self.eat("+") {
+                return self.unary();
+            }
+            if self.eat("!") {
+                return Ok(i64::from(self.unary()? == 0));
+            }
+            if self.eat("~") {
+                return Ok(!self.unary()?);
+            }
+            self.primary()
+        }
+
+        /// A number, a variable, `i++`, `i--` or an expression in parentheses.
+        fn primary(&mut self) -> Result<i64> {
+            match self.peek().cloned() {
+                Some(Token::Number(n)) => {
+                    self.current += 1;
+                    Ok(n)
+                }
+                Some(Token::Name(name)) => {
+                    self.current += 1;
+                    let value = self.var(&name)?;
+                    if self.eat("++") {
+                        self.set_var(&name, value.wrapping_add(1))?;
+                    } else if self.eat("--") {
+                        self.set_var(&name, value.wrapping_sub(1))?;
+                    }
+                    Ok(value)
+                }
+                Some(Token::Operator("(")) => {
+                    self.current += 1;
+                    let value = self.comma()?;
+                    self.expect(")")?;
+                    Ok(value)
+                }
+                _ => Err(self.syntax_error()),
+            }
+        }
+
+        /// The value of a variable. Unset and empty variables are 0.
+        fn var(&mut self, name: &str) -> Result<i64> {
+            let value = self.shell.var(name).unwrap_or_default().trim().to_string();
+            if value.is_empty() {
+                return Ok(0);
+            }
+            if let Ok(n) = parse_number(&value) {
+                return Ok(n);
+            }
+            if self.depth >= MAX_DEPTH {
+                return Err(format!("{name}: expression recursion level exceeded").into());
+            }
+            eval_nested(&value, self.shell, self.depth + 1)
+        }
+
+        fn set_var(&mut self, name: &str, value: i64) -> Result<i64> {
+            if !self.skip {
+                self.shell.assign(name, value.to_string())?;
+            }
+            Ok(value)
+        }
+
+        fn apply(&self, op: &str, a: i64, b: i64) -> Result<i64> {
+            Ok(match op {
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                "/" | "%" if b == 0 => {
+                    if self.skip {
+                        return Ok(0);
+                    }
+                    return Err("division by 0".into());
+                }
+                "/" => a.wrapping_div(b),
+                "%" => a.wrapping_rem(b),
+                "**" if b < 0 => return Err("exponent less than 0".into()),
+                "**" => a.wrapping_pow(b.try_into().unwrap_or(u32::MAX)),
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                "&" => a & b,
+                "^" => a ^ b,
+                "|" => a | b,
+                "<" => i64::from(a < b),
+                "<=" => i64::from(a <= b),
+                ">" => i64::from(a > b),
+                ">=" => i64::from(a >= b),
+                "==" => i64::from(a == b),
+                "!=" => i64::from(a != b),
+                "&&" => i64::from(a != 0 && b != 0),
+                "||" => i64::from(a != 0 || b != 0),
+                _ => unreachable!("unknown arithmetic operator {op}"),
+            })
+        }
+    }
+}
+
+/// Brace expansion, which generates multiple words from one word.
+///
+/// A comma separated list like `config.{yaml,yaml.bak}` generates one word
+/// for each item: `config.yaml config.yaml.bak`. Lists can be nested,
+/// like `{a,b{1,2}}`. A sequence like `{1..10}` or `{a..e}` generates
+/// a range of numbers or letters, optionally with a step, like `{0..100..10}`.
+/// Numbers are padded with zeros if one of the bounds has a leading zero,
+/// so `{01..10}` generates `01 02 ... 10`.
+///
+/// Braces that don't form a valid list or sequence, like `{a}` or `{}`,
+/// are kept as they are.
+mod braces {
+    use crate::WordPart;
+
+    /// Expand the braces of a word, given as its parts.
+    /// The unquoted text of the word must be split into single characters.
+    pub fn expand(parts: &[WordPart]) -> Vec<Vec<WordPart>> {
+        for start in 0..parts.len() {
+            if !is_char(&parts[start], '{') {
+                continue;
+            }
+            let Some((end, items)) = parse(parts, start) else {
+                continue;
+            };
+            // The items may contain more braces, and so may the rest of the word.
+            let mut words = vec![];
+            for item in items {
+                let mut word = parts[..start].to_vec();
+                word.extend(item);
+                word.extend_from_slice(&parts[end + 1..]);
+                words.extend(expand(&word));
+            }
+            return words;
+        }
+        vec![parts.to_vec()]
+    }
+
+    fn is_char(part: &WordPart, c: char) -> bool {
+        matches!(part, WordPart::Literal(text) if text.starts_with(c) && text.len() == c.len_utf8())
+    }
+
+    /// Parse the brace expression that starts at `start`.
+    /// Returns the index of the closing brace and the generated items.
+    fn parse(parts: &[WordPart], start: usize) -> Option<(usize, Vec<Vec<WordPart>>)> {
+        let mut depth = 0;
+        let mut items = vec![];
+        let mut item_start = start + 1;
+        for (i, part) in parts.iter().enumerate().skip(start + 1) {
+            if is_char(part, '{') {
+                depth += 1;
+            } else if is_char(part, '}') && depth > 0 {
+                depth -= 1;
+            } else if is_char(part, ',') && depth == 0 {
+                items.push(parts[item_start..i].to_vec());
+                item_start = i + 1;
+            } else if is_char(part, '}') {
+                if !items.is_empty() {
+                    items.push(parts[item_start..i].to_vec());
+                    return Some((i, items));
+                }
+                // Without a comma, this can only be a sequence like `{1..5}`.
+                let mut text = String::new();
+                for part in &parts[start + 1..i] {
+                    match part {
+                        WordPart::Literal(c) => text.push_str(c),
+                        _ => return None,
+                    }
+                }
+                let items = sequence(&text)?;
+                let items = items.into_iter().map(|item| vec![WordPart::Literal(item)]);
+                return Some((i, items.collect()));
+            }
+        }
+        None
+    }
+
+    /// Generate the items of a sequence like `1..10`, `01..20..2` or `a..z`.
+    fn sequence(text: &str) -> Option<Vec<String>> {
+        let (start, end, step) = match text.split("..").collect::<Vec<_>>()[..] {
+            [start, end] => (start, end, 1),
+            [start, end, step] => (start, end, step.parse::<i64>().ok()?),
+            _ => return None,
+        };
+        // The sign of the step doesn't matter, only the order of the bounds does.
+        let step = step.abs().max(1);
+        if let (Ok(from), Ok(to)) = (start.parse::<i64>(), end.parse::<i64>()) {
+            let has_leading_zero = |n: &str| {
+                let digits = n.trim_start_matches('-');
+                digits.len() > 1 && digits.starts_with('0')
+            };
+            let width = if has_leading_zero(start) || has_leading_zero(end) {
+                start.len().max(end.len())
+            } else {
+                0
+            };
+            let numbers = range(from, to, step).map(|n| format!("{n:0width$}"));
+            return Some(numbers.collect());
+        }
+        let letter = |text: &str| {
+            let mut chars = text.chars();
+            let c = chars.next().filter(char::is_ascii_alphabetic)?;
+            chars.next().is_none().then_some(c as i64)
+        };
+        let letters = range(letter(start)?, letter(end)?, step);
+        Some(letters.map(|c| char::from(c as u8).to_string()).collect())
+    }
+
+    /// The numbers from `from` to `to`, in steps of `step`,
+    /// counting down if `to` is less than `from`.
+    fn range(from: i64, to: i64, step: i64) -> impl Iterator<Item = i64> {
+        let step = if from <= to { step } else { -step };
+        std::iter::successors(Some(from), move |n| n.checked_add(step)).take_while(move |&n| {
+            if step > 0 {
+                n <= to
+            } else {
+                n >= to
+            }
+        })
+    }
+}
+
+/// Tab completion for the line editor.
+///
+/// The lexer splits the line up to the cursor into tokens, just like for
+/// running it, which tells us where the word under the cursor starts and
+/// whether it is the name of a command. Depending on that, we complete:
+///
+/// - `%1`: the number of a job,
+/// - `$HO`: the name of a variable,
+/// - `ec`, as a command name: a builtin or a program in `PATH`,
+/// - anything else: a path. `~/` stands for the home directory, and
+///   the completed path is quoted the way the word was, like `"my file.txt"`.
+mod complete {
+    use crate::{builtins, is_executable, passwd_home, Lexer, Redirect, Shell, Token, WordPart};
+    use rustyline::{
+        completion::{Completer, Pair},
+        highlight::Highlighter,
+        hint::Hinter,
+        validate::Validator,
+        Context, Helper,
+    };
+    use std::{collections::HashMap, fs, path::Path};
+
+    /// What tab completion knows about the shell.
+    ///
+    /// The line editor owns it, so it gets a copy of the state
+    /// of the shell before each prompt.
+    #[derive(Default)]
+    pub struct Completion {
+        /// The shell variables, for `$NAME`, `PATH` and `HOME`.
+        pub vars: HashMap<String, String>,
+        /// The numbers and command lines of the jobs, for `%1`.
+        pub jobs: Vec<(usize, String)>,
+    }
+
+    impl Completion {
+        /// Copy the state of the shell that completion needs.
+        pub fn update(&mut self, shell: &Shell) {
+            self.vars = shell.vars.clone();
+            self.jobs = shell
+                .jobs
+                .iter()
+                .map(|job| (job.id, job.command.clone()))
+                .collect();
+        }
+
+        /// Complete the word that ends at the end of `line`.
+        ///
+        /// Returns where the text to replace starts, in bytes,
+        /// and the candidates to replace it with.
+        pub fn complete(&self, line: &str) -> (usize, Vec<Pair>) {
+            let (start, command_position) = current_word(line);
+            let word = &line[start..];
+            let quote = open_quote(word);
+            if let Some(spec) = word.strip_prefix('%') {
+                return (start, self.job_specs(spec));
+            }
+            // A `$` in single quotes is just a `$`.
+            if let Some(dollar) = word.rfind('$').filter(|_| quote != Some('\'')) {
+                let name = &word[dollar + 1..];
+                let (braced, name) = match name.strip_prefix('{') {
+                    Some(name) => (true, name),
+                    None => (false, name),
+                };
+                if name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric()) {
+                    let start = start + word.len() - name.len();
+                    return (start, self.variables(name, braced));
+                }
+            }
+            let Some(value) = unquote(word, quote) else {
+                return (start, vec![]);
+            };
+            let candidates = if command_position && !value.contains('/') && !value.starts_with('~')
+            {
+                self.commands(&value)
+                    .into_iter()
+                    .map(|name| Pair {
+                        replacement: render(&name, quote, false),
+                        display: name,
+                    })
+                    .collect()
+            } else {
+                self.paths(&value)
+                    .into_iter()
+                    .map(|(path, is_dir)| Pair {
+                        display: Path::new(&path)
+                            .file_name()
+                            .map_or(path.clone(), |name| name.to_string_lossy().into_owned())
+                            + if is_dir { "/" } else { "" },
+                        replacement: render(&path, quote, is_dir),
+                    })
+                    .collect()
+            };
+            (start, candidates)
+        }
+
+        /// Job specs like `%1` for the jobs whose number starts with `spec`.
+        fn job_specs(&self, spec: &str) -> Vec<Pair> {
+            self.jobs
+                .iter()
+                .filter(|(id, _)| id.to_string().starts_with(spec))
+                .map(|(id, command)| Pair {
+                    display: format!("%{id}  {command}"),
+                    replacement: format!("%{id} "),
+                })
+                .collect()
+        }
+
+        /// The names of the variables that start with `prefix`.
+        fn variables(&self, prefix: &str, braced: bool) -> Vec<Pair> {
+            let mut names: Vec<&String> = self
+                .vars
+                .keys()
+                .filter(|name| name.starts_with(prefix))
+                .collect();
+            names.sort();
+            names
+                .into_iter()
+                .map(|name| Pair {
+                    display: name.clone(),
+                    replacement: if braced {
+                        format!("{name}}}")
+                    } else {
+                        name.clone()
+                    },
+                })
+                .collect()
+        }
+
+        /// The builtins and the programs in the directories of `PATH`
+        /// whose names start with `prefix`.
+        fn commands(&self, prefix: &str) -> Vec<String> {
+            let mut names: Vec<String> = builtins::NAMES
+                .iter()
+                .filter(|name| name.starts_with(prefix))
+                .map(|name| name.to_string())
+                .collect();
+            let path = self.vars.get("PATH").map_or("", String::as_str);
+            for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+                let Ok(entries) = fs::read_dir(dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with(prefix) && is_executable(&entry.path()) {
+                        names.push(name);
+                    }
+                }
+            }
+            names.sort();
+            names.dedup();
+            names
+        }
+
+        /// The paths that start with `prefix`, and whether they are directories,
+        /// which end with a `/`.
+        ///
+        /// Like for patterns, hidden files are only completed if the file
+        /// name starts with a `.`.
+        fn paths(&self, prefix: &str) -> Vec<(String, bool)> {
+            let (dir, file) = match prefix.rfind('/') {
+                Some(i) => prefix.split_at(i + 1),
+                None => ("", prefix),
+            };
+            let search_dir = match dir.strip_prefix('~') {
+                Some(rest) => {
+                    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+                    let home = match user {
+                        "" => self.vars.get("HOME").cloned(),
+                        _ => passwd_home(user),
+                    };
+                    let Some(home) = home else {
+                        return vec![];
+                    };
+                    format!("{home}{rest}")
+                }
+                None if dir.is_empty() => ".".to_string(),
+                None => dir.to_string(),
+            };
+            let Ok(entries) = fs::read_dir(&search_dir) else {
+                return vec![];
+            };
+            let mut paths: Vec<(String, bool)> = entries
+                .flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    let visible = !name.starts_with('.') || file.starts_with('.');
+                    (visible && name.starts_with(file)).then(|| {
+                        let is_dir = fs::metadata(entry.path()).is_ok_and(|m| m.is_dir());
+                        let slash = if is_dir { "/" } else { "" };
+                        (format!("{dir}{name}{slash}"), is_dir)
+                    })
+                })
+                .collect();
+            paths.sort();
+            paths
+        }
+    }
+
+    /// Find the word that ends at the end of `line`, using the lexer of the shell.
+    ///
+    /// Returns where the word starts, in bytes, and whether it is the name of
+    /// a command: the first word after `|`, `;` and the like, that is not an
+    /// assignment like `A=1` or the target of a redirection like `> file`.
+    fn current_word(line: &str) -> (usize, bool) {
+        let mut lexer = Lexer::new(line);
+        let mut tokens = vec![];
+        // Where the word starts, in chars, if we are in the middle of one.
+        let mut word_start = None;
+        loop {
+            lexer.skip_whitespace();
+            let start = lexer.current;
+            match lexer.next_token() {
+                Ok(Some(token)) => {
+                    // A word that goes up to the end of the line is the one we complete.
+                    let is_last_word =
+                        matches!(token, Token::Word(_)) && lexer.current == lexer.chars.len();
+                    if is_last_word {
+                        word_start = Some(start);
+                    } else {
+                        tokens.push(token);
+                    }
+                }
+                Ok(None) => break,
+                // An unterminated quote: the word goes up to the end of the line.
+                Err(_) => {
+                    word_start = Some(start);
+                    break;
+                }
+            }
+        }
+        let start = match word_start {
+            Some(start) => line
+                .char_indices()
+                .nth(start)
+                .map_or(line.len(), |(i, _)| i),
+            None => line.len(),
+        };
+
+        let mut command_started = false;
+        let mut redirect_target = false;
+        for token in tokens {
+            match token {
+                Token::Operator(op) if Redirect::is_operator(&op) => redirect_target = true,
+                Token::Operator(_) | Token::Newline => {
+                    command_started = false;
+                    redirect_target = false;
+                }
+                Token::Word(_) if redirect_target => redirect_target = false,
+                Token::Word(word) => command_started |= word.as_assignment().is_none(),
+                Token::Arithmetic(_) => command_started = true,
+                Token::HereDoc(_) => {}
+            }
+        }
+        (start, !command_started && !redirect_target)
+    }
+
+    /// The quote that is still open at the end of `word`, if any.
+    fn open_quote(word: &str) -> Option<char> {
+        let mut quote = None;
+        let mut chars = word.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (None | Some('"'), '\\') => {
+                    chars.next();
+                }
+                (None, '\'' | '"') => quote = Some(c),
+                (Some(open), c) if c == open => quote = None,
+                _ => {}
+            }
+        }
+        quote
+    }
+
+    /// The text of `word` without quotes and backslashes, after closing `quote`.
+    ///
+    /// Returns `None` if the word contains an expansion like `$HOME`,
+    /// because we only complete what the user typed literally.
+    fn unquote(word: &str, quote: Option<char>) -> Option<String> {
+        let closed = format!("{word}{}", quote.map(String::from).unwrap_or_default());
+        let word = Lexer::new(&closed).read_word().ok()?;
+        let mut text = String::new();
+        for part in word.0 {
+            match part {
+                WordPart::Literal(s) | WordPart::Quoted(s) => text.push_str(&s),
+                WordPart::DoubleQuoted(parts) => {
+                    for part in parts {
+                        match part {
+                            WordPart::Literal(s) | WordPart::Quoted(s) => text.push_str(&s),
+                            _ => return None,
+                        }
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(text)
+    }
+
+    /// Quote `text` for the command line, within `quote` if the word was quoted.
+    ///
+    /// A leading `~user/` stays unquoted, so that it still expands.
+    /// The quote is closed and followed by a space, unless `text` is a directory
+    /// that the user probably wants to complete further.
+    fn render(text: &str, quote: Option<char>, is_dir: bool) -> String {
+        let (tilde, text) = match text.find('/') {
+            Some(i) if text.starts_with('~') => text.split_at(i + 1),
+            _ => ("", text),
+        };
+        let mut rendered = tilde.to_string();
+        match quote {
+            Some('\'') => {
+                rendered.push('\'');
+                rendered.push_str(&text.replace('\'', "'\\''"));
+            }
+            Some(_) => {
+                rendered.push('"');
+                for c in text.chars() {
+                    if matches!(c, '"' | '\\' | '$' | '`') {
+                        rendered.push('\\');
+                    }
+                    rendered.push(c);
+                }
+            }
+            None => {
+                for c in text.chars() {
+                    // `#` and `~` are only special at the start of a word.
+                    let at_start = rendered.is_empty() && "#~".contains(c);
+                    if at_start || c.is_whitespace() || "\\'\"$`&;|<>()*?[]{}".contains(c) {
+                        rendered.push('\\');
+                    }
+                    rendered.push(c);
+                }
+            }
+        }
+        if !is_dir {
+            rendered.extend(quote);
+            rendered.push(' ');
+        }
+        rendered
+    }
+
+    impl Completer for Completion {
+        type Candidate = Pair;
+
+        fn complete(
+            &self,
+            line: &str,
+            pos: usize,
+            _: &Context<'_>,
+        ) -> rustyline::Result<(usize, Vec<Pair>)> {
+            Ok(Completion::complete(self, &line[..pos]))
+        }
+    }
+
+    // The line editor also supports hints, highlighting and validation,
+    // which we don't use.
+    impl Hinter for Completion {
+        type Hint = String;
+    }
+
+    impl Highlighter for Completion {}
+
+    impl Validator for Completion {}
+
+    impl Helper for Completion {}
+}
+
+/// Pathname expansion, which finds the files that match a pattern like `src/*.rs`.
+///
+/// The pattern is matched one path component at a time, so a `*` never
+/// matches a `/`. A `**` component matches any number of directories:
+/// `src/**/*.rs` finds Rust files in `src` and all of its subdirectories.
+/// Like in other shells, files that start with a `.` are hidden, unless
+/// the pattern component starts with a `.` as well.
+mod glob {
+    use crate::pattern;
+    use std::{fs, path::Path};
+
+    /// Find the paths that match `pattern`, in sorted order.
+    pub fn expand(pattern: &str) -> Vec<String> {
+        let components: Vec<&str> = pattern.split('/').collect();
+        let mut paths = vec![];
+        expand_in("", &components, &mut paths);
+        paths.sort();
+        paths
+    }
+
+    /// Find the paths in `dir` that match the pattern `components`.
+    /// `dir` is empty for the current directory, or ends with a `/`.
+    fn expand_in(dir: &str, components: &[&str], paths: &mut Vec<String>) {
+        let Some((&component, rest)) = components.split_first() else {
+            return;
+        };
+        if component == "**" {
+            // `**` can match no directory at all...
+            if !rest.is_empty() {
+                expand_in(dir, rest, paths);
+            }
+            // ...or any subdirectory, followed by more directories.
+            for name in entries(dir, "") {
+                let path = format!("{dir}{name}");
+                if rest.is_empty() {
+                    paths.push(path.clone());
+                }
+                // Symbolic links are not followed, so that a link to
+                // a parent directory doesn't make us loop forever.
+                if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+                    expand_in(&format!("{path}/"), components, paths);
+                }
+            }
+        } else if !pattern::has_wildcards(component) {
+            // Without wildcards, there is no need to read the directory.
+            // This also handles the empty components of `/usr` and `src/`.
+            let path = format!("{dir}{}", pattern::unescape(component));
+            if !rest.is_empty() {
+                expand_in(&format!("{path}/"), rest, paths);
+            } else if fs::symlink_metadata(&path).is_ok() {
+                paths.push(path);
+            }
+        } else {
+            for name in entries(dir, component) {
+                if !pattern::matches(component, &name) {
+                    continue;
+                }
+                let path = format!("{dir}{name}");
+                if rest.is_empty() {
+                    paths.push(path);
+                } else if Path::new(&path).is_dir() {
+                    expand_in(&format!("{path}/"), rest, paths);
+                }
+            }
+        }
+    }
+
+    /// The names of the entries in `dir` that the pattern `component` may match.
+    /// Directories that can't be read have no entries.
+    fn entries(dir: &str, component: &str) -> Vec<String> {
+        let dir = if dir.is_empty() { "." } else { dir };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return vec![];
+        };
+        let show_hidden = component.starts_with('.') || component.starts_with("\\.");
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| show_hidden || !name.starts_with('.'))
+            .collect()
+    }
+}
+
+/// Whether `name` can be used as a variable name:
+/// letters, digits and underscores, not starting with a digit.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Whether `name` is a special parameter like `?`,
+/// or a positional parameter like `1`.
+fn is_special_param(name: &str) -> bool {
+    matches!(name, "?" | "$" | "!" | "#" | "@" | "*")
+        || (!name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Options that change the behavior of the shell, set with `shopt`.
+#[derive(Clone)]
+struct Options {
+    /// Patterns that match no files expand to nothing, instead of themselves.
+    nullglob: bool,
+    /// Patterns that match no files are an error.
+    failglob: bool,
+    /// An interactive shell doesn't exit at the end of the input (Ctrl-D),
+    /// so that it isn't closed by accident. `exit` still works.
+    ignoreeof: bool,
+    /// The line editor uses key bindings like Emacs, e.g. Ctrl-A moves to the start of the line.
+    emacs: bool,
+    /// The line editor uses key bindings like vi, e.g. Escape switches to command mode.
+    vi: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            nullglob: false,
+            failglob: false,
+            ignoreeof: false,
+            emacs: true,
+            vi: false,
+        }
+    }
+}
+
+impl Options {
+    /// The names and values of all options.
+    fn all(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("emacs", self.emacs),
+            ("failglob", self.failglob),
+            ("ignoreeof", self.ignoreeof),
+            ("nullglob", self.nullglob),
+            ("vi", self.vi),
+        ]
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "failglob" => Some(&mut self.failglob),
+            "ignoreeof" => Some(&mut self.ignoreeof),
+            "emacs" => Some(&mut self.emacs),
+            "vi" => Some(&mut self.vi),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
+
+/// The state of the shell that lives longer than a single command.
+struct Shell {
+    /// The shell variables, which start out as a copy of the environment.
+    vars: HashMap<String, String>,
+    /// The names of the variables that commands get as environment variables.
+    /// A name can be exported before the variable is set.
+    exported: HashSet<String>,
+    /// The names of the variables that can't be assigned or unset.
+    readonly: HashSet<String>,
+    /// The exit status of the last command, `$?`.
+    last_status: i32,
+    /// The process ID of the last command run in the background, `$!`.
+    last_background_pid: Option<u32>,
+    /// The name of the shell, `$0`.
+    name: String,
+    /// The positional parameters `$1`, `$2`, and so on.
+    positional: Vec<String>,
+    options: Options,
+    /// The directory stack of `pushd` and `popd`, without the current
+    /// directory, which is always on top of the stack.
+    dir_stack: Vec<String>,
+    /// The jobs that run in the background or are stopped, the current job last.
+    jobs: Vec<Job>,
+    /// The process group of the shell, if job control is enabled.
+    pgid: Option<Pid>,
+    /// Whether we are running a command substitution, where `exit` doesn't exit the shell.
+    in_substitution: bool,
+    /// The status of an `exit` in a command substitution,
+    /// which skips the rest of the substitution.
+    exit_status: Option<i32>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        let mut args = std::env::args();
+        let mut vars: HashMap<String, String> = std::env::vars().collect();
+        // An inherited `PWD` is only kept if it still points to the current directory.
+        if let Ok(dir) = std::env::current_dir() {
+            let is_current =
+                |pwd: &String| std::fs::canonicalize(pwd).ok() == std::fs::canonicalize(&dir).ok();
+            if !vars.get("PWD").is_some_and(is_current) {
+                vars.insert("PWD".to_string(), dir.to_string_lossy().into_owned());
+            }
+        }
+        Self {
+            // Everything we inherited is passed on to the commands we run.
+            exported: vars.keys().cloned().collect(),
+            readonly: HashSet::new(),
+            vars,
+            last_status: 0,
+            last_background_pid: None,
+            name: args.next().unwrap_or_else(|| "rush".to_string()),
+            positional: args.collect(),
+            options: Options::default(),
+            dir_stack: vec![],
+            jobs: vec![],
+            pgid: None,
+            in_substitution: false,
+            exit_status: None,
+        }
+    }
+
+    fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// Look up a parameter: a special parameter like `?`,
+    /// a positional parameter like `1`, or a variable.
+    fn param(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            // `"$*"` joins the parameters with the first character of `IFS`.
+            "*" => {
+                let separator = self.ifs().chars().next().map(String::from);
+                Some(self.positional.join(&separator.unwrap_or_default()))
+            }
+            _ => match name.parse::<usize>() {
+                // `$0` and `${00}` are the name of the shell.
+                Ok(0) => Some(self.name.clone()),
+                Ok(n) => self.positional.get(n - 1).cloned(),
+                Err(_) => self.var(name).map(String::from),
+            },
+        }
+    }
+
+    /// The directory that the tilde prefix `~user` stands for.
+    ///
+    /// `~` is `$HOME`, `~+` is the current directory `$PWD` and `~-` is
+    /// the previous directory `$OLDPWD`. Otherwise, we look up the home
+    /// directory of the user in `/etc/passwd`.
+    fn home_dir(&self, user: &str) -> Option<String> {
+        match user {
+            "" => self.var("HOME").map(String::from),
+            "+" => self.var("PWD").map(String::from).or_else(|| {
+                let dir = std::env::current_dir().ok()?;
+                dir.into_os_string().into_string().ok()
+            }),
+            "-" => self.var("OLDPWD").map(String::from),
+            _ => passwd_home(user),
+        }
+    }
+
+    /// The characters that split the results of expansions into fields.
+    /// Without an `IFS` variable, these are space, tab and newline.
+    fn ifs(&self) -> &str {
+        self.var("IFS").unwrap_or(" \t\n")
+    }
+
+    fn set_var(&mut self, name: &str, value: String) {
+        self.vars.insert(name.to_string(), value);
+    }
+
+    /// Assign a variable like `NAME=value` does, which fails for readonly variables.
+    fn assign(&mut self, name: &str, value: String) -> Result<()> {
+        if self.readonly.contains(name) {
+            return Err(format!("{name}: readonly variable").into());
+        }
+        self.set_var(name, value);
+        Ok(())
+    }
+
+    /// Add a job to the job table as the current job, and return its number.
+    ///
+    /// A new job gets a number that is one more than the highest number in use,
+    /// while a job that was in the table before keeps its number.
+    fn add_job(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        self.jobs.push(job);
+        id
+    }
+
+    /// Wait for a job in the foreground and return its exit status.
+    ///
+    /// With job control, the job gets the terminal while it runs, so that
+    /// Ctrl-Z and Ctrl-C reach its processes instead of the shell.
+    /// A job that was stopped goes to the job table, and its status is
+    /// 128 plus the signal number of `SIGTSTP`, like for other signals.
+    fn wait_in_foreground(&mut self, mut job: Job) -> i32 {
+        self.give_terminal_to(job.pgid);
+        let state = job.wait();
+        self.give_terminal_to(self.pgid);
+        match state {
+            // The terminal printed `^C` without a newline, so we add one for the prompt.
+            // A builtin like `fg` only passes on the status of a job that already did that.
+            JobState::Done(status) if status == signal_status(Signal::SIGINT as i32) => {
+                if self.pgid.is_some() && job.pgid.is_some() {
+                    eprintln!();
+                }
+                status
+            }
+            JobState::Done(status) => status,
+            JobState::Running | JobState::Stopped => {
+                self.add_job(job);
+                let len = self.jobs.len();
+                eprintln!("\n{}", self.jobs[len - 1].describe('+'));
+                signal_status(Signal::SIGTSTP as i32)
+            }
+        }
+    }
+
+    /// Make `pgid` the foreground process group of the terminal,
+    /// if job control is enabled.
+    fn give_terminal_to(&self, pgid: Option<Pid>) {
+        if let (Some(_), Some(pgid)) = (self.pgid, pgid) {
+            if let Err(e) = tcsetpgrp(io::stdin().as_raw_fd(), pgid) {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+
+    /// Enable job control, which only makes sense if stdin is a terminal.
+    ///
+    /// The shell puts itself into its own process group, which owns the terminal
+    /// while no job runs in the foreground. It ignores the signals that would stop it:
+    /// `SIGTSTP` from Ctrl-Z, and `SIGTTIN` and `SIGTTOU`, which the terminal sends
+    /// to background processes that read from it or change its settings.
+    fn enable_job_control(&mut self) -> Result<()> {
+        let stdin = io::stdin().as_raw_fd();
+        // If we were started in the background, wait until we are in the foreground.
+        while tcgetpgrp(stdin)? != getpgrp() {
+            killpg(getpgrp(), Signal::SIGTTIN)?;
+        }
+        for signal in [Signal::SIGTSTP, Signal::SIGTTIN, Signal::SIGTTOU] {
+            // SAFETY: `SigIgn` doesn't install a Rust function as signal handler.
+            unsafe { nix::sys::signal::signal(signal, SigHandler::SigIgn) }?;
+        }
+        let pid = Pid::this();
+        // This fails if the shell is a session leader, which is already a group leader.
+        let _ = setpgid(pid, pid);
+        tcsetpgrp(stdin, getpgrp())?;
+        self.pgid = Some(getpgrp());
+        Ok(())
+    }
+
+    /// The marker of the job at `index` in a table with `len` jobs:
+    /// `+` for the current job and `-` for the previous one.
+    fn job_marker(index: usize, len: usize) -> char {
+        match len - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    /// Print the background jobs that finished since the last prompt,
+    /// and remove them from the table.
+    fn report_jobs(&mut self) {
+        let len = self.jobs.len();
+        for (i, job) in self.jobs.iter_mut().enumerate() {
+            if let JobState::Done(_) = job.poll() {
+                eprintln!("{}", job.describe(Self::job_marker(i, len)));
+            }
+        }
+        self.jobs
+            .retain(|job| !matches!(job.state, JobState::Done(_)));
+    }
+
+    /// The exported variables that are set, sorted by name.
+    fn env(&self) -> Vec<(String, String)> {
+        let mut env: Vec<(String, String)> = self
+            .exported
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.var(name)?.to_string())))
+            .collect();
+        env.sort();
+        env
+    }
+}
+
+/// Look up the home directory of `user` in `/etc/passwd`.
+fn passwd_home(user: &str) -> Option<String> {
+    // Each line looks like `name:password:uid:gid:info:home:shell`.
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.first() == Some(&user))
+            .then(|| fields.get(5).map(|home| home.to_string()))
+            .flatten()
+    })
+}
+
+fn main() {
+    let mut shell = Shell::new();
+    if io::stdin().is_terminal() {
+        if let Err(e) = shell.enable_job_control() {
+            eprintln!("Error: job control is disabled: {}", e);
+        }
+        if let Err(e) = handle_keyboard_signals() {
+            eprintln!("Error: {}", e);
+        }
+    }
+    let history = builtins::History::new();
+    let mut input = Input::new(&history);
+    'input: loop {
+        shell.report_jobs();
+        let Some(mut line) = input.read_line(&mut shell) else {
+            continue;
+        };
+        // At the end of the input, we exit with the status of the last command,
+        // like after running a script.
+        if line.is_empty() {
+            let interactive = io::stdin().is_terminal();
+            if interactive && shell.options.ignoreeof {
+                eprintln!("Use \"exit\" to leave the shell.");
+                continue;
+            }
+            if interactive {
+                eprintln!("exit");
+            }
+            std::process::exit(shell.last_status);
+        }
+        let chains = loop {
+            match chains_from_line(&line) {
+                Ok(chains) => break chains,
+                // Here-documents and quotes can span multiple lines,
+                // so we need to read more input to complete the command.
+                Err(e) if e.is::<Incomplete>() => {
+                    let Some(next_line) = input.read_line(&mut shell) else {
+                        continue 'input;
+                    };
+                    if next_line.is_empty() {
+                        eprintln!("Error: {}", e);
+                        // Like bash, we report the syntax error with status 2.
+                        shell.last_status = 2;
+                        break vec![];
+                    }
+                    line.push_str(&next_line);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    shell.last_status = 2;
+                    break vec![];
+                }
+            }
+        };
+        history.add(line.trim()).expect("Cannot open history file");
+        input.add_history(line.trim());
+        for chain in chains {
+            chain.run(&mut shell, &Io::Stdout);
+        }
+    }
+}
+
+/// Where the shell reads its input from.
+enum Input {
+    /// A line editor, if the shell runs in a terminal.
+    ///
+    /// It supports moving the cursor, key bindings like Emacs or vi
+    /// (`set -o vi`), and going through the history with the arrow keys.
+    Editor(Box<Editor<complete::Completion, DefaultHistory>>),
+    /// Plain lines, like when a script is piped into the shell.
+    Plain,
+}
+
+impl Input {
+    /// Use a line editor if stdin and stdout are a terminal, and plain lines otherwise.
+    ///
+    /// The line editor starts with the commands of the `history` file.
+    fn new(history: &builtins::History) -> Self {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Input::Plain;
+        }
+        // Like bash, Tab lists the candidates if there is more than one.
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .build();
+        match Editor::with_config(config) {
+            Ok(mut editor) => {
+                editor.set_helper(Some(complete::Completion::default()));
+                // There is no history yet when the shell runs for the first time.
+                let _ = editor.load_history(history.path());
+                Input::Editor(Box::new(editor))
+            }
+            Err(e) => {
+                eprintln!("Error: can't start the line editor: {}", e);
+                Input::Plain
+            }
+        }
+    }
+
+    /// Show a prompt and read a line, or return `None` if Ctrl-C abandoned it.
+    ///
+    /// At the end of the input, like after Ctrl-D, the line is empty.
+    /// Otherwise, it ends with a newline, even if it was read by the line editor.
+    fn read_line(&mut self, shell: &mut Shell) -> Option<String> {
+        let Input::Editor(editor) = self else {
+            show_prompt();
+            return read_line(shell);
+        };
+        if let Some(completion) = editor.helper_mut() {
+            completion.update(shell);
+        }
+        editor.set_edit_mode(if shell.options.vi {
+            EditMode::Vi
+        } else {
+            EditMode::Emacs
+        });
+        match editor.readline("> ") {
+            Ok(line) => Some(line + "\n"),
+            Err(ReadlineError::Interrupted) => {
+                shell.last_status = signal_status(Signal::SIGINT as i32);
+                None
+            }
+            Err(ReadlineError::Eof) => Some(String::new()),
+            Err(e) => panic!("failed to read line from stdin: {e}"),
+        }
+    }
+
+    /// Add a command to the history of the line editor, for the arrow keys.
+    fn add_history(&mut self, command: &str) {
+        if let Input::Editor(editor) = self {
+            let _ = editor.add_history_entry(command);
+        }
+    }
+}
+
+/// If `stdout` is printed to a terminal, print a prompt.
+/// Otherwise, do nothing. This allows to redirect the shell `stdout`
+/// to a file or another process, without the prompt being printed.
+fn show_prompt() {
+    let mut stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        write!(stdout, "> ").unwrap();
+        // Flush stdout to ensure the prompt is displayed.
+        stdout.flush().expect("can't flush stdout");
+    }
+}
+
+/// Read a line from stdin, or return `None` if Ctrl-C abandoned it.
+fn read_line(shell: &mut Shell) -> Option<String> {
+    let line = if io::stdin().is_terminal() {
+        read_terminal_line()
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map(|_| line)
+    };
+    match line {
+        Ok(line) => Some(line),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+            // Like bash, continue on a new line, with `$?` set to 128 + `SIGINT`.
+            println!();
+            shell.last_status = signal_status(Signal::SIGINT as i32);
+            None
+        }
+        Err(e) => panic!("failed to read line from stdin: {e}"),
+    }
+}
+
+/// Read a line from the terminal with a single `read`.
+///
+/// In its default (canonical) mode, the terminal hands out input one line
+/// at a time, once Enter is pressed. Unlike `Stdin::read_line`, we don't
+/// retry if `read` was interrupted by a signal, so Ctrl-C abandons the line.
+fn read_terminal_line() -> io::Result<String> {
+    let mut buffer = [0; 4096];
+    let len = nix::unistd::read(io::stdin().as_raw_fd(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer[..len]).into_owned())
+}
+
+/// Handle the signals that are sent by the keyboard, like in bash.
+///
+/// Ctrl-C sends `SIGINT` and Ctrl-\ sends `SIGQUIT` to the foreground process
+/// group of the terminal. While a job runs in the foreground, that's the job,
+/// so its processes are interrupted. The shell itself ignores `SIGQUIT`, and
+/// `SIGINT` only abandons the line that it is reading.
+fn handle_keyboard_signals() -> Result<()> {
+    // The handler does nothing: it only exists so that `read` fails with `EINTR`,
+    // which it wouldn't for an ignored signal. Without `SA_RESTART`, the kernel
+    // doesn't restart the `read` after the handler ran.
+    extern "C" fn interrupt(_: std::ffi::c_int) {}
+    let action = SigAction::new(
+        SigHandler::Handler(interrupt),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler does nothing, so it's safe to run at any time.
+    unsafe { sigaction(Signal::SIGINT, &action) }?;
+    // SAFETY: `SigIgn` doesn't install a Rust function as signal handler.
+    unsafe { nix::sys::signal::signal(Signal::SIGQUIT, SigHandler::SigIgn) }?;
+    Ok(())
+}
+
+fn chains_from_line(line: &str) -> Result<Vec<Chain>> {
+    Parser::new(line).and_then(Parser::parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_chains(line: &str) -> Vec<Chain> {
+        chains_from_line(line).unwrap()
+    }
+
+    /// An unquoted word without expansions.
+    fn word(text: &str) -> Word {
+        Word(vec![WordPart::Literal(text.to_string())])
+    }
+
+    /// A command without redirections, made of unquoted words.
+    fn cmd(words: &[&str]) -> Cmd {
+        Cmd {
+            assignments: vec![],
+            words: words.iter().map(|w| word(w)).collect(),
+            redirects: vec![],
+        }
+    }
+
+    /// A command without redirections, made of the given words.
+    fn cmd_of(words: Vec<Word>) -> Cmd {
+        Cmd {
+            assignments: vec![],
+            words,
+            redirects: vec![],
+        }
+    }
+
+    /// Create a fresh directory for a test, containing the given files.
+    fn temp_dir(name: &str, files: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("rush-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+        dir.to_str().unwrap().to_string()
+    }
+
+    /// Expand the words of every command in `line` into their arguments.
+    fn expand_args(shell: &mut Shell, line: &str) -> Vec<Vec<String>> {
+        let mut args = vec![];
+        for chain in parse_chains(line) {
+            for element in chain.elements {
+                if let Element::Cmd(cmd) = element {
+                    let mut cmd_args = vec![];
+                    for word in &cmd.words {
+                        cmd_args.extend(word.expand(shell).unwrap());
+                    }
+                    args.push(cmd_args);
+                }
+            }
+        }
+        args
+    }
+
+    #[test]
+    fn no_cmd_is_parsed_from_empty_line() {
+        assert_eq!(parse_chains(""), vec![]);
+    }
+
+    #[test]
+    fn cmd_with_no_args_is_parsed() {
+        assert_eq!(
+            parse_chains("ls"),
+            vec![Chain {
+                elements: vec![Element::Cmd(cmd(&["ls"]))]
+            },]
+        );
+    }
+
+    #[test]
+    fn cmd_with_args_is_parsed() {
+        assert_eq!(
+            parse_chains("ls -l"),
+            vec![Chain {
+                elements: vec![Element::Cmd(cmd(&["ls", "-l"]))]
+            }]
+        );
+    }
+
+    #[test]
+    fn cmds_are_parsed() {
+        assert_eq!(
+            parse_chains("ls; echo hello"),
+            vec![
+                Chain {
+                    elements: vec![Element::Cmd(cmd(&["ls"]))]
+                },
+                Chain {
+                    elements: vec![Element::Cmd(cmd(&["echo", "hello"]))]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pipe_is_parsed() {
+        assert_eq!(
+            parse_chains("ls | wc -l"),
+            vec![Chain {
+                elements: vec![
+                    Element::Cmd(cmd(&["ls"])),
+                    Element::Pipe,
+                    Element::Cmd(cmd(&["wc", "-l"])),
+                ]
+            }]
+        );
+    }
+
+    #[test]
+    fn quoted_args_are_parsed() {
+        assert_eq!(
+            expand_args(
+                &mut Shell::new(),
+                r#"git commit -m 'fix bug' --author "A \"B\" C""#
+            ),
+            vec![vec![
+                "git",
+                "commit",
+                "-m",
+                "fix bug",
+                "--author",
+                r#"A "B" C"#
+            ]]
+        );
+    }
+
+    #[test]
+    fn backslash_escapes_are_parsed() {
+        assert_eq!(
+            expand_args(&mut Shell::new(), r#"a\ b "\$x\y" '\n' \|"#),
+            vec![vec!["a b", r"$x\y", r"\n", "|"]]
+        );
+    }
+
+    #[test]
+    fn empty_quotes_are_an_empty_arg() {
+        assert_eq!(
+            expand_args(&mut Shell::new(), "echo '' \"\""),
+            vec![vec!["echo", "", ""]]
+        );
+    }
+
+    #[test]
+    fn operators_need_no_whitespace() {
+        assert_eq!(
+            Lexer::new("ls|wc -l&&echo ok").tokenize().unwrap(),
+            vec![
+                Token::Word(word("ls")),
+                Token::Operator("|".to_string()),
+                Token::Word(word("wc")),
+                Token::Word(word("-l")),
+                Token::Operator("&&".to_string()),
+                Token::Word(word("echo")),
+                Token::Word(word("ok")),
+            ]
+        );
+        assert_eq!(
+            Lexer::new("sleep 1&echo a&b").tokenize().unwrap(),
+            vec![
+                Token::Word(word("sleep")),
+                Token::Word(word("1")),
+                Token::Operator("&".to_string()),
+                Token::Word(word("echo")),
+                Token::Word(word("a")),
+                Token::Operator("&".to_string()),
+                Token::Word(word("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_quote_is_an_error() {
+        assert!(Lexer::new("echo 'hello").tokenize().is_err());
+        assert!(Lexer::new("echo \"hello").tokenize().is_err());
+    }
+
+    #[test]
+    fn quoted_semicolons_dont_split_chains() {
+        assert_eq!(
+            expand_args(&mut Shell::new(), r"echo 'a;b'; find . -exec rm {} \;"),
+            vec![
+                vec!["echo", "a;b"],
+                vec!["find", ".", "-exec", "rm", "{}", ";"]
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_chains_are_skipped() {
+        assert_eq!(parse_chains(";;"), vec![]);
+        assert_eq!(parse_chains("ls;").len(), 1);
+    }
+
+    #[test]
+    fn redirects_are_parsed() {
+        assert_eq!(
+            parse_chains("make > build.log 2>&1"),
+            vec![Chain {
+                elements: vec![Element::Cmd(Cmd {
+                    redirects: vec![
+                        Redirect::new(1, RedirectTarget::Write(word("build.log"))),
+                        Redirect::new(2, RedirectTarget::Dup(1)),
+                    ],
+                    ..cmd(&["make"])
+                })]
+            }]
+        );
+    }
+
+    #[test]
+    fn redirects_can_come_first() {
+        assert_eq!(
+            parse_chains("<data.txt sort>>out 2<in"),
+            vec![Chain {
+                elements: vec![Element::Cmd(Cmd {
+                    redirects: vec![
+                        Redirect::new(0, RedirectTarget::Read(word("data.txt"))),
+                        Redirect::new(1, RedirectTarget::Append(word("out"))),
+                        Redirect::new(2, RedirectTarget::Read(word("in"))),
+                    ],
+                    ..cmd(&["sort"])
+                })]
+            }]
+        );
+    }
+
+    #[test]
+    fn only_standard_fds_can_be_redirected() {
+        for line in [
+            "sort 3<in",
+            "echo hi 3>out",
+            "echo hi >&3",
+            "cat 0<&9",
+            "echo hi 4294967297>out",
+            "echo hi 99999999999999999999>/dev/null",
+        ] {
+            assert!(chains_from_line(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn digits_are_only_a_fd_before_redirects() {
+        assert_eq!(
+            Lexer::new("echo 2 2>&1 a2>b").tokenize().unwrap(),
+            vec![
+                Token::Word(word("echo")),
+                Token::Word(word("2")),
+                Token::Operator("2>&".to_string()),
+                Token::Word(word("1")),
+                Token::Word(word("a2")),
+                Token::Operator(">".to_string()),
+                Token::Word(word("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn redirect_without_target_is_an_error() {
+        assert!(Parser::new("echo >").unwrap().parse().is_err());
+        assert!(Parser::new("echo 2>&x").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn heredocs_are_parsed() {
+        let chains =
+            parse_chains("cat <<EOF; cat <<-'END'\n  hello $USER\nEOF\n\t\tbye $USER\n\tEND\n");
+        let heredoc = |chain: &Chain| match &chain.elements[0] {
+            Element::Cmd(cmd) => match &cmd.redirects[0].target {
+                RedirectTarget::HereDoc(body) => body.clone(),
+                target => panic!("unexpected redirect {target:?}"),
+            },
+            element => panic!("unexpected element {element:?}"),
+        };
+        assert_eq!(
+            heredoc(&chains[0]),
+            Word(vec![WordPart::DoubleQuoted(vec![
+                WordPart::Literal("  hello ".to_string()),
+                WordPart::Param(Param {
+                    name: "USER".to_string(),
+                    op: ParamOp::Value
+                }),
+                WordPart::Literal("\n".to_string()),
+            ])])
+        );
+        assert_eq!(
+            heredoc(&chains[1]),
+            Word(vec![WordPart::Quoted("bye $USER\n".to_string())])
+        );
+    }
+
+    #[test]
+    fn unterminated_heredoc_is_incomplete() {
+        let err = chains_from_line("cat <<EOF\nhello\n").unwrap_err();
+        assert!(err.is::<Incomplete>());
+    }
+
+    #[test]
+    fn trailing_backslash_is_incomplete() {
+        for line in ["echo one \\\n", "echo one\\"] {
+            let err = chains_from_line(line).unwrap_err();
+            assert!(err.is::<Incomplete>(), "{line}");
+        }
+        assert_eq!(
+            parse_chains("echo one \\\ntwo\n"),
+            vec![Chain {
+                elements: vec![Element::Cmd(cmd(&["echo", "one", "two"]))]
+            }]
+        );
+    }
+
+    #[test]
+    fn here_string_is_parsed() {
+        assert_eq!(
+            parse_chains("wc -c <<< 'a b'"),
+            vec![Chain {
+                elements: vec![Element::Cmd(Cmd {
+                    redirects: vec![Redirect::new(
+                        0,
+                        RedirectTarget::HereString(Word(vec![WordPart::Quoted("a b".to_string())]))
+                    )],
+                    ..cmd(&["wc", "-c"])
+                })]
+            }]
+        );
+    }
+
+    #[test]
+    fn variables_are_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("NAME", "a b".to_string());
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                r#"echo $NAME "${NAME}s" '$NAME' \$NAME $ $UNSET"#
+            ),
+            vec![vec!["echo", "a", "b", "a bs", "$NAME", "$NAME", "$"]]
+        );
+    }
+
+    #[test]
+    fn assignments_are_parsed() {
+        assert_eq!(
+            parse_chains("A=1 B=\"$A\"2"),
+            vec![Chain {
+                elements: vec![Element::Cmd(Cmd {
+                    assignments: vec![
+                        Assignment {
+                            name: "A".to_string(),
+                            value: word("1"),
+                        },
+                        Assignment {
+                            name: "B".to_string(),
+                            value: Word(vec![
+                                WordPart::DoubleQuoted(vec![WordPart::Param(Param {
+                                    name: "A".to_string(),
+                                    op: ParamOp::Value
+                                })]),
+                                WordPart::Literal("2".to_string()),
+                            ]),
+                        },
+                    ],
+                    ..cmd(&[])
+                })]
             }]
         );
     }
+
+    #[test]
+    fn quoted_names_are_not_assignments() {
+        assert_eq!(
+            expand_args(&mut Shell::new(), "'A'=1 1A=2"),
+            vec![vec!["A=1", "1A=2"]]
+        );
+    }
+
+    #[test]
+    fn special_params_are_expanded() {
+        let mut shell = Shell::new();
+        shell.last_status = 3;
+        shell.positional = vec!["a".to_string(), "b c".to_string()];
+        assert_eq!(
+            expand_args(&mut shell, "echo $? $# $1 \"${2}\" $3 $@ $*"),
+            vec![vec![
+                "echo", "3", "2", "a", "b c", "a", "b", "c", "a", "b", "c"
+            ]]
+        );
+        assert_eq!(
+            expand_args(&mut shell, "echo $$"),
+            vec![vec!["echo".to_string(), std::process::id().to_string()]]
+        );
+        shell.name = "rush".to_string();
+        assert_eq!(
+            expand_args(&mut shell, "echo $0 ${00} ${01}"),
+            vec![vec!["echo", "rush", "rush", "a"]]
+        );
+    }
+
+    #[test]
+    fn param_defaults_are_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("EMPTY", String::new());
+        shell.set_var("SET", "x".to_string());
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo ${UNSET:-a b} ${EMPTY:-d} ${EMPTY-d} ${SET:+alt} ${UNSET+alt} ${NEW:=new} $NEW"
+            ),
+            vec![vec!["echo", "a", "b", "d", "alt", "new", "new"]]
+        );
+        assert_eq!(shell.var("NEW"), Some("new"));
+        let chain = parse_chains("echo ${UNSET:?is missing}").remove(0);
+        let Element::Cmd(cmd) = &chain.elements[0] else {
+            unreachable!()
+        };
+        let err = cmd.words[1].expand(&mut shell).unwrap_err();
+        assert_eq!(err.to_string(), "UNSET: is missing");
+    }
+
+    #[test]
+    fn param_patterns_are_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("FILE", "src/main.tar.gz".to_string());
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo ${#FILE} ${FILE#*/} ${FILE%.*} ${FILE%%.*} ${FILE##*.} ${FILE#\"*\"}"
+            ),
+            vec![vec![
+                "echo",
+                "15",
+                "main.tar.gz",
+                "src/main.tar",
+                "src/main",
+                "gz",
+                "src/main.tar.gz"
+            ]]
+        );
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo ${FILE/a/A} ${FILE//a/A} ${FILE/#src/lib} ${FILE/%gz/xz} ${FILE//[.\\/]}"
+            ),
+            vec![vec![
+                "echo",
+                "src/mAin.tar.gz",
+                "src/mAin.tAr.gz",
+                "lib/main.tar.gz",
+                "src/main.tar.xz",
+                "srcmaintargz"
+            ]]
+        );
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo ${FILE:4} ${FILE:4:4} ${FILE: -2} ${FILE:0:-3}"
+            ),
+            vec![vec!["echo", "main.tar.gz", "main", "gz", "src/main.tar"]]
+        );
+        assert_eq!(
+            expand_args(&mut shell, "echo ${FILE:4:9223372036854775807}"),
+            vec![vec!["echo", "main.tar.gz"]]
+        );
+    }
+
+    #[test]
+    fn patterns_match() {
+        assert!(pattern::matches("*.rs", "main.rs"));
+        assert!(pattern::matches("a*b*c", "aXbYbc"));
+        assert!(pattern::matches("[a-c]?[!0-9]", "bxy"));
+        assert!(!pattern::matches("[a-c]?[!0-9]", "bx1"));
+        assert!(pattern::matches("\\*", "*"));
+        assert!(!pattern::matches("\\*", "a"));
+        assert!(pattern::matches("[]]", "]"));
+        assert!(!pattern::matches("*.rs", "main.rsx"));
+    }
+
+    #[test]
+    fn command_substitutions_are_parsed() {
+        let chains = parse_chains("echo $(echo \"a)\" $(pwd)) `ls \\`pwd\\``");
+        let Element::Cmd(echo) = &chains[0].elements[0] else {
+            unreachable!()
+        };
+        let nested = Chain {
+            elements: vec![Element::Cmd(cmd_of(vec![
+                word("echo"),
+                Word(vec![WordPart::DoubleQuoted(vec![WordPart::Literal(
+                    "a)".to_string(),
+                )])]),
+                Word(vec![WordPart::CommandSub(vec![Chain {
+                    elements: vec![Element::Cmd(cmd(&["pwd"]))],
+                }])]),
+            ]))],
+        };
+        assert_eq!(
+            echo.words[1],
+            Word(vec![WordPart::CommandSub(vec![nested])])
+        );
+        let backquoted = Chain {
+            elements: vec![Element::Cmd(cmd_of(vec![
+                word("ls"),
+                Word(vec![WordPart::CommandSub(vec![Chain {
+                    elements: vec![Element::Cmd(cmd(&["pwd"]))],
+                }])]),
+            ]))],
+        };
+        assert_eq!(
+            echo.words[2],
+            Word(vec![WordPart::CommandSub(vec![backquoted])])
+        );
+    }
+
+    #[test]
+    fn unterminated_command_substitution_is_incomplete() {
+        for line in ["echo $(echo", "echo `echo"] {
+            let err = chains_from_line(line).unwrap_err();
+            assert!(err.is::<Incomplete>(), "{line}");
+        }
+    }
+
+    #[test]
+    fn command_substitutions_are_split() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo x$(echo a b)y \"$(printf 'a  b')\" $(printf '\\n\\n') \"`printf 'c\\n\\n'`\""
+            ),
+            vec![vec!["echo", "xa", "by", "a  b", "c"]]
+        );
+        assert_eq!(shell.last_status, 0);
+        expand_args(&mut shell, "echo $(false)");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(
+            expand_args(&mut shell, r#"echo "`echo \"hi\"`" `echo \"hi\"`"#),
+            vec![vec!["echo", "hi", "\"hi\""]]
+        );
+    }
+
+    #[test]
+    fn command_substitution_assignments_are_undone() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            expand_args(&mut shell, "echo $(INNER=1; echo $INNER)"),
+            vec![vec!["echo", "1"]]
+        );
+        assert_eq!(shell.var("INNER"), None);
+        let positional = shell.positional.clone();
+        expand_args(&mut shell, "echo $(set -- a; pushd -n /a; set -o vi)");
+        assert_eq!(shell.positional, positional);
+        assert!(shell.dir_stack.is_empty());
+        assert!(!shell.options.vi);
+    }
+
+    #[test]
+    fn exit_only_ends_a_command_substitution() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo $(echo a; exit 3; echo b)$(true && exit 1 || echo c)"
+            ),
+            vec![vec!["echo", "a"]]
+        );
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.exit_status, None);
+        assert!(!shell.in_substitution);
+    }
+
+    #[test]
+    fn patterns_are_expanded_to_paths() {
+        let dir = temp_dir(
+            "glob",
+            &[
+                "b.rs",
+                "a.rs",
+                ".hidden.rs",
+                "c.txt",
+                "src/d.rs",
+                "src/x/e.rs",
+            ],
+        );
+        let mut shell = Shell::new();
+        let paths = |names: &[&str]| -> Vec<String> {
+            names.iter().map(|name| format!("{dir}/{name}")).collect()
+        };
+        let args = |shell: &mut Shell, line: &str| expand_args(shell, line).remove(0);
+        assert_eq!(
+            args(&mut shell, &format!("{dir}/*.rs")),
+            paths(&["a.rs", "b.rs"])
+        );
+        assert_eq!(
+            args(&mut shell, &format!("{dir}/.*.rs {dir}/[!a].?s")),
+            paths(&[".hidden.rs", "b.rs"])
+        );
+        assert_eq!(args(&mut shell, &format!("{dir}/*/")), paths(&["src/"]));
+        assert_eq!(
+            args(&mut shell, &format!("{dir}/**/*.rs")),
+            paths(&["a.rs", "b.rs", "src/d.rs", "src/x/e.rs"])
+        );
+        assert_eq!(
+            args(&mut shell, &format!("{dir}/src/**")),
+            paths(&["src/d.rs", "src/x", "src/x/e.rs"])
+        );
+        // Quoted wildcards match literally, and patterns without matches are kept.
+        assert_eq!(
+            args(&mut shell, &format!("'{dir}'/\"*\".rs {dir}/*.md")),
+            vec![format!("{dir}/*.rs"), format!("{dir}/*.md")]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unmatched_patterns_follow_the_options() {
+        let mut shell = Shell::new();
+        let chain = parse_chains("shopt -s nullglob").remove(0);
+        chain.run(&mut shell, &Io::Null);
+        assert!(shell.options.nullglob);
+        assert_eq!(
+            expand_args(&mut shell, "echo /nonexistent/*.md"),
+            vec![vec!["echo"]]
+        );
+        shell.options.failglob = true;
+        let chain = parse_chains("echo /nonexistent/*.md").remove(0);
+        let Element::Cmd(echo) = &chain.elements[0] else {
+            unreachable!()
+        };
+        let err = echo.words[1].expand(&mut shell).unwrap_err();
+        assert_eq!(err.to_string(), "no match: /nonexistent/*.md");
+    }
+
+    #[test]
+    fn tildes_are_parsed() {
+        let chain = parse_chains("echo ~ ~+/src a~ ~\"x\" PATH=~/bin:~user:/x~").remove(0);
+        let Element::Cmd(echo) = &chain.elements[0] else {
+            unreachable!()
+        };
+        let tilde = |user: &str| WordPart::Tilde(user.to_string());
+        let literal = |text: &str| WordPart::Literal(text.to_string());
+        assert_eq!(echo.words[1], Word(vec![tilde("")]));
+        assert_eq!(echo.words[2], Word(vec![tilde("+"), literal("/src")]));
+        assert_eq!(echo.words[3], word("a~"));
+        assert_eq!(
+            echo.words[4],
+            Word(vec![
+                literal("~"),
+                WordPart::DoubleQuoted(vec![literal("x")])
+            ])
+        );
+        let assignment = echo.words[5].as_assignment().unwrap();
+        assert_eq!(
+            assignment.value,
+            Word(vec![
+                tilde(""),
+                literal("/bin:"),
+                tilde("user"),
+                literal(":/x~")
+            ])
+        );
+    }
+
+    #[test]
+    fn tildes_are_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("HOME", "/home/my user".to_string());
+        shell.set_var("OLDPWD", "/old".to_string());
+        assert_eq!(
+            expand_args(&mut shell, "echo ~ ~/src ~- ~nonexistent-user/x"),
+            vec![vec![
+                "echo",
+                "/home/my user",
+                "/home/my user/src",
+                "/old",
+                "~nonexistent-user/x"
+            ]]
+        );
+        parse_chains("P=~/bin:~-")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert_eq!(shell.var("P"), Some("/home/my user/bin:/old"));
+    }
+
+    #[test]
+    fn cd_paths_are_normalized() {
+        let normalize = |path: &str| builtins::Cd::normalize(std::path::Path::new(path));
+        assert_eq!(normalize("/a/./b/../c/"), std::path::Path::new("/a/c"));
+        assert_eq!(normalize("/../a/.."), std::path::Path::new("/"));
+    }
+
+    #[test]
+    fn cd_searches_cdpath() {
+        let dir = temp_dir("cdpath", &["work/project/file", "project/file"]);
+        let cdpath = format!("/nonexistent:{dir}/work:");
+        let search = |name: &str| builtins::Cd::search_cdpath(name, Some(&cdpath));
+        assert_eq!(
+            search("project"),
+            (
+                std::path::PathBuf::from(format!("{dir}/work/project")),
+                true
+            )
+        );
+        assert_eq!(search("./project"), ("./project".into(), false));
+        assert_eq!(search("missing"), ("missing".into(), false));
+        assert_eq!(
+            builtins::Cd::search_cdpath("project", None),
+            ("project".into(), false)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dir_stack_is_managed() {
+        let mut shell = Shell::new();
+        shell.set_var("PWD", "/home/me".to_string());
+        shell.set_var("HOME", "/home/me".to_string());
+        let mut run = |line: &str| Chain::output(&parse_chains(line), &mut shell).unwrap();
+        assert_eq!(run("pushd -n /a"), "~ /a");
+        assert_eq!(run("pushd -n /home/me/b"), "~ ~/b /a");
+        assert_eq!(run("dirs -l -v"), " 0  /home/me\n 1  /home/me/b\n 2  /a");
+        assert_eq!(run("dirs -p"), "~\n~/b\n/a");
+        assert_eq!(run("dirs +1; dirs -0"), "~/b\n/a");
+        assert_eq!(run("popd -n"), "~ /a");
+        assert_eq!(run("popd -n +1"), "~");
+        assert_eq!(run("popd; echo $?"), "1");
+        assert_eq!(run("dirs +1; echo $?"), "1");
+        assert_eq!(run("dirs ''; dirs ü1; popd ''; echo $?"), "1");
+    }
+
+    #[test]
+    fn braces_are_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("V", "v".to_string());
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo a{b,c}d {a,{b,c}}x pre{$V,'q r'} {a,b}{1,2} {a} {} \"{x,y}\" \\{x,y} {,a}"
+            ),
+            vec![vec![
+                "echo", "abd", "acd", "ax", "bx", "cx", "prev", "preq r", "a1", "a2", "b1", "b2",
+                "{a}", "{}", "{x,y}", "{x,y}", "a"
+            ]]
+        );
+    }
+
+    #[test]
+    fn brace_sequences_are_expanded() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo {1..3} {3..1} {01..10..3} {-2..2..-2} {c..a} {a..e..2} {1..3..0}x {1..a}"
+            ),
+            vec![vec![
+                "echo", "1", "2", "3", "3", "2", "1", "01", "04", "07", "10", "-2", "0", "2", "c",
+                "b", "a", "a", "c", "e", "1x", "2x", "3x", "{1..a}"
+            ]]
+        );
+    }
+
+    #[test]
+    fn arithmetic_is_evaluated() {
+        let mut shell = Shell::new();
+        let mut eval = |expression: &str| arith::eval(expression, &mut shell).unwrap();
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("2 ** 3 ** 2"), 512);
+        assert_eq!(eval("-2 ** 2"), 4);
+        assert_eq!(eval("7 / 2 + -7 % 3"), 2);
+        assert_eq!(eval("1 << 4 | 1"), 17);
+        assert_eq!(eval("5 > 3 && 2 < 1 || !0"), 1);
+        assert_eq!(eval("010 + 0x10 + 2#11"), 27);
+        assert_eq!(eval(""), 0);
+        assert_eq!(eval("x = 5, x += 2, x * 2"), 14);
+        assert_eq!(eval("x++ + ++x"), 16);
+        assert_eq!(eval("x > 5 ? 10 : 20"), 10);
+        assert_eq!(eval("0 && (y = 1 / 0), 1 || (y = 2), y"), 0);
+        assert_eq!(eval("z = x < 5 ? 1 : x > 8 ? 2 : 3"), 2);
+        assert_eq!(shell.var("x"), Some("9"));
+        assert_eq!(shell.var("y"), None);
+
+        shell.set_var("A", "B + 1".to_string());
+        shell.set_var("B", "2".to_string());
+        assert_eq!(arith::eval("A * 2", &mut shell).unwrap(), 6);
+        for invalid in ["1 / 0", "1 +", "(1", "1 2", "2 ** -1", "09", "a = = 1"] {
+            assert!(arith::eval(invalid, &mut shell).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn arithmetic_is_expanded() {
+        let mut shell = Shell::new();
+        shell.set_var("x", "4".to_string());
+        shell.set_var("S", "abcdef".to_string());
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo $((1 + (2))) \"$(( x * $x ))\" $(( $(echo 3) - 1 )) ${S:x-3:1+1}"
+            ),
+            vec![vec!["echo", "3", "16", "2", "bc"]]
+        );
+    }
+
+    #[test]
+    fn arithmetic_commands_set_the_status() {
+        let mut shell = Shell::new();
+        let mut run = |line: &str| {
+            parse_chains(line).remove(0).run(&mut shell, &Io::Null);
+            shell.last_status
+        };
+        assert_eq!(run("(( 0 ))"), 1);
+        assert_eq!(run("((i = 2 + 3))"), 0);
+        assert_eq!(run("let j=i*2 'j < 5'"), 1);
+        assert_eq!(run("(( 1 / 0 ))"), 1);
+        assert_eq!(shell.var("j"), Some("10"));
+    }
+
+    #[test]
+    fn expansions_are_split_by_ifs() {
+        let mut shell = Shell::new();
+        shell.set_var("ARGS", "  -l \t -a  ".to_string());
+        shell.set_var("PATHS", "/bin::/usr/bin:".to_string());
+        assert_eq!(
+            expand_args(&mut shell, "ls $ARGS x${ARGS}y \"$ARGS\" ${#ARGS}"),
+            vec![vec![
+                "ls",
+                "-l",
+                "-a",
+                "x",
+                "-l",
+                "-a",
+                "y",
+                "  -l \t -a  ",
+                "11"
+            ]]
+        );
+        shell.set_var("IFS", ": ".to_string());
+        assert_eq!(
+            expand_args(&mut shell, "echo $PATHS $ARGS"),
+            vec![vec!["echo", "/bin", "", "/usr/bin", "-l", "\t", "-a"]]
+        );
+        shell.set_var("IFS", String::new());
+        assert_eq!(
+            expand_args(&mut shell, "echo $ARGS"),
+            vec![vec!["echo", "  -l \t -a  "]]
+        );
+        shell.vars.remove("IFS");
+        assert_eq!(
+            expand_args(
+                &mut shell,
+                "echo ${UNSET:-a b} ${UNSET:-\"c d\" e} ${ARGS:+f g} \"${UNSET:-h i}\" ${NEW:=j k}"
+            ),
+            vec![vec![
+                "echo", "a", "b", "c d", "e", "f", "g", "h i", "j", "k"
+            ]]
+        );
+    }
+
+    #[test]
+    fn positional_params_are_separate_words() {
+        let mut shell = Shell::new();
+        shell.positional = vec!["a b".to_string(), String::new(), "c".to_string()];
+        assert_eq!(
+            expand_args(&mut shell, "echo \"$@\" x\"$@\"y $@ \"$*\""),
+            vec![vec![
+                "echo", "a b", "", "c", "xa b", "", "cy", "a", "b", "c", "a b  c"
+            ]]
+        );
+        shell.set_var("IFS", ",".to_string());
+        assert_eq!(
+            expand_args(&mut shell, "echo \"$*\""),
+            vec![vec!["echo", "a b,,c"]]
+        );
+        shell.positional.clear();
+        assert_eq!(
+            expand_args(&mut shell, "echo \"$@\" \"$*\""),
+            vec![vec!["echo", ""]]
+        );
+    }
+
+    #[test]
+    fn assignments_can_prefix_commands() {
+        let chain = parse_chains("A=1 B=$A env A=2").remove(0);
+        let Element::Cmd(env) = &chain.elements[0] else {
+            unreachable!()
+        };
+        let names: Vec<&str> = env.assignments.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["A", "B"]);
+        assert_eq!(env.words, cmd(&["env", "A=2"]).words);
+
+        let mut shell = Shell::new();
+        shell.set_var("A", "outer".to_string());
+        let output = Chain::capture(&parse_chains("A=1 B=$A env"), &mut shell).unwrap();
+        assert!(output.lines().any(|line| line == "A=1"));
+        assert!(output.lines().any(|line| line == "B=1"));
+        // The assignments only apply to the command.
+        parse_chains("A=1 B=2 true")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert_eq!(shell.var("A"), Some("outer"));
+        assert_eq!(shell.var("B"), None);
+    }
+
+    #[test]
+    fn only_exported_variables_reach_commands() {
+        let mut shell = Shell::new();
+        let run = |shell: &mut Shell, line: &str| {
+            for chain in parse_chains(line) {
+                chain.run(shell, &Io::Null);
+            }
+        };
+        run(&mut shell, "LOCAL=1; export SHARED=2 LATER; LATER=3");
+        let output = Chain::capture(&parse_chains("env"), &mut shell).unwrap();
+        assert!(!output.lines().any(|line| line.starts_with("LOCAL=")));
+        assert!(output.lines().any(|line| line == "SHARED=2"));
+        assert!(output.lines().any(|line| line == "LATER=3"));
+
+        run(&mut shell, "export -n SHARED; unset LATER");
+        assert_eq!(shell.var("SHARED"), Some("2"));
+        assert_eq!(shell.var("LATER"), None);
+        let output = Chain::capture(&parse_chains("env"), &mut shell).unwrap();
+        assert!(!output.lines().any(|line| line.starts_with("SHARED=")));
+        let output = Chain::capture(&parse_chains("export -p"), &mut shell).unwrap();
+        assert!(!output.lines().any(|line| line.starts_with("export SHARED")));
+    }
+
+    #[test]
+    fn programs_are_found_in_the_path_variable() {
+        let sh = Some(PathBuf::from("/bin/sh"));
+        assert_eq!(find_program("sh", Some("/nonexistent::/bin")), sh);
+        assert_eq!(find_program("sh", None), None);
+        assert_eq!(find_program("./sh", None), Some(PathBuf::from("./sh")));
+
+        let mut shell = Shell::new();
+        for chain in parse_chains("unset PATH; sh -c true") {
+            chain.run(&mut shell, &Io::Null);
+        }
+        assert_eq!(shell.last_status, 127);
+    }
+
+    #[test]
+    fn readonly_variables_cant_change() {
+        let mut shell = Shell::new();
+        for chain in parse_chains("readonly FIXED=\"it's\"; FIXED=2; unset FIXED; let FIXED=3") {
+            chain.run(&mut shell, &Io::Null);
+        }
+        assert_eq!(shell.var("FIXED"), Some("it's"));
+        let output = Chain::capture(&parse_chains("readonly"), &mut shell).unwrap();
+        assert!(output
+            .lines()
+            .any(|line| line == "readonly FIXED='it'\\''s'"));
+        shell.readonly.insert("EMPTY".to_string());
+        assert!(Chain::capture(&parse_chains("echo ${EMPTY:=x}"), &mut shell).is_ok());
+        assert_eq!(shell.var("EMPTY"), None);
+    }
+
+    #[test]
+    fn set_replaces_positional_params() {
+        let mut shell = Shell::new();
+        parse_chains("set -- a 'b c'")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert_eq!(shell.positional, ["a", "b c"]);
+        let output = Chain::capture(&parse_chains("set"), &mut shell).unwrap();
+        assert!(output.lines().any(|line| line.starts_with("PWD='")));
+        parse_chains("set --").remove(0).run(&mut shell, &Io::Null);
+        assert!(shell.positional.is_empty());
+    }
+
+    #[test]
+    fn ampersand_terminates_a_chain() {
+        let chains = parse_chains("sleep 1 | cat & echo hi");
+        assert_eq!(chains.len(), 2);
+        assert_eq!(
+            chains[0].elements,
+            [
+                Element::Cmd(cmd(&["sleep", "1"])),
+                Element::Pipe,
+                Element::Cmd(cmd(&["cat"])),
+                Element::Background,
+            ]
+        );
+        assert_eq!(chains[1].elements, [Element::Cmd(cmd(&["echo", "hi"]))]);
+        assert!(chains_from_line("&").is_err());
+    }
+
+    #[test]
+    fn operators_need_commands() {
+        for line in [
+            "| ls",
+            "echo a &&",
+            "ls &&& ls",
+            "ls || | wc",
+            "echo a |; ls",
+        ] {
+            assert!(chains_from_line(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn background_jobs_are_tracked() {
+        let mut shell = Shell::new();
+        parse_chains("sleep 0.1 &")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert!(shell.last_background_pid.is_some());
+        assert_eq!(shell.jobs.len(), 1);
+        assert_eq!(shell.jobs[0].command, "sleep 0.1");
+        assert_eq!(
+            shell.jobs[0].describe('+'),
+            "[1]+  Running                 sleep 0.1 &"
+        );
+
+        parse_chains("false &").remove(0).run(&mut shell, &Io::Null);
+        assert_eq!(shell.jobs[1].id, 2);
+        let start = std::time::Instant::now();
+        while shell
+            .jobs
+            .iter_mut()
+            .any(|job| job.poll() == JobState::Running)
+        {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(
+            shell.jobs[1].describe('+'),
+            "[2]+  Exit 1                  false"
+        );
+        shell.report_jobs();
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn background_chains_run_in_a_subshell() {
+        let mut shell = Shell::new();
+        let cwd = std::env::current_dir().unwrap();
+        for chain in parse_chains("X=1; cd / && X=2 && exit 5 &") {
+            chain.run(&mut shell, &Io::Null);
+        }
+        assert_eq!(shell.last_status, 0);
+        let start = std::time::Instant::now();
+        while shell.jobs[0].poll() == JobState::Running {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(
+            shell.jobs[0].describe('+'),
+            "[1]+  Exit 5                  cd / && X=2 && exit 5"
+        );
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+        assert_eq!(shell.var("X"), Some("1"));
+    }
+
+    #[test]
+    fn chains_are_shown_as_typed() {
+        let chain = parse_chains("make&&./run  2>&1|tee -a log >>out &").remove(0);
+        assert_eq!(
+            chain.to_string(),
+            "make && ./run 2>&1 | tee -a log >> out &"
+        );
+        for line in [
+            "A=1 B=\"x y\" env <in 2>&1 &> out <<< 'a b'",
+            "echo ${x:-a b} ${#x} ${x##*/} ${x//a/b} ${x: -2:1} ${1}0 $10 ${x}y $x.y",
+            "echo $((i + 1)) $(pwd; sleep 1 & ls) ~user/src 'it'\\''s' a\\ b \"$x \\$ \\\"q\\\"\"",
+        ] {
+            let chain = parse_chains(line).remove(0);
+            assert_eq!(parse_chains(&chain.to_string()), [chain], "{line}");
+        }
+    }
+
+    #[test]
+    fn stopped_jobs_can_be_continued() {
+        let mut shell = Shell::new();
+        let run = |shell: &mut Shell, line: &str| {
+            parse_chains(line).remove(0).run(shell, &Io::Null);
+        };
+        let poll_until = |shell: &mut Shell, state: JobState| {
+            let start = std::time::Instant::now();
+            while shell.jobs[0].poll() != state {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        run(&mut shell, "sleep 5 &");
+        shell.jobs[0].signal(Signal::SIGSTOP).unwrap();
+        poll_until(&mut shell, JobState::Stopped);
+        assert_eq!(
+            shell.jobs[0].describe('+'),
+            "[1]+  Stopped                 sleep 5"
+        );
+        run(&mut shell, "bg %sleep");
+        assert_eq!(shell.last_status, 0);
+        poll_until(&mut shell, JobState::Running);
+        shell.jobs[0].signal(Signal::SIGKILL).unwrap();
+        poll_until(&mut shell, JobState::Done(128 + Signal::SIGKILL as i32));
+
+        run(&mut shell, "fg %2");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn foreground_jobs_can_be_stopped() {
+        let mut shell = Shell::new();
+        parse_chains("sh -c 'kill -STOP $$; exit 3'")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert_eq!(shell.last_status, 128 + Signal::SIGTSTP as i32);
+        assert_eq!(
+            shell.jobs[0].describe('+'),
+            "[1]+  Stopped                 sh -c kill -STOP $$; exit 3"
+        );
+        parse_chains("%1").remove(0).run(&mut shell, &Io::Null);
+        assert_eq!(shell.last_status, 3);
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn commands_dont_inherit_ignored_signals() {
+        let mut shell = Shell::new();
+        // SAFETY: `SigIgn` and `SigDfl` don't install Rust functions as signal handlers.
+        unsafe { nix::sys::signal::signal(Signal::SIGQUIT, SigHandler::SigIgn) }.unwrap();
+        parse_chains("sh -c 'kill -QUIT $$'")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        unsafe { nix::sys::signal::signal(Signal::SIGQUIT, SigHandler::SigDfl) }.unwrap();
+        assert_eq!(shell.last_status, 128 + Signal::SIGQUIT as i32);
+    }
+
+    #[test]
+    fn set_o_changes_options() {
+        let mut shell = Shell::new();
+        parse_chains("set -o ignoreeof")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert!(shell.options.ignoreeof);
+        let output = Chain::capture(&parse_chains("set -o"), &mut shell).unwrap();
+        assert!(output.lines().any(|line| line == "ignoreeof      \ton"));
+        parse_chains("set +o ignoreeof")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert!(!shell.options.ignoreeof);
+        parse_chains("set -o nosuchoption")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn only_one_key_binding_is_on() {
+        let mut shell = Shell::new();
+        assert!(shell.options.emacs && !shell.options.vi);
+        parse_chains("set -o vi")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert!(!shell.options.emacs && shell.options.vi);
+        parse_chains("set -o emacs")
+            .remove(0)
+            .run(&mut shell, &Io::Null);
+        assert!(shell.options.emacs && !shell.options.vi);
+    }
+
+    #[test]
+    fn words_are_completed_by_context() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir(
+            "complete",
+            &[
+                "my file.txt",
+                "my dir/inner",
+                "other",
+                ".hidden",
+                "bin/tool",
+            ],
+        );
+        let tool = format!("{dir}/bin/tool");
+        std::fs::set_permissions(tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let completion = complete::Completion {
+            vars: HashMap::from([
+                ("HOME".to_string(), dir.clone()),
+                ("HOST".to_string(), "box".to_string()),
+                ("PATH".to_string(), format!("{dir}/bin")),
+            ]),
+            jobs: vec![(1, "sleep 10".to_string())],
+        };
+        let complete = |line: &str| {
+            let (start, candidates) = completion.complete(line);
+            let replacements: Vec<String> = candidates.into_iter().map(|c| c.replacement).collect();
+            (start, replacements)
+        };
+
+        // Command names are builtins or programs in `PATH`.
+        assert_eq!(complete("ex"), (0, vec!["exit ".into(), "export ".into()]));
+        assert_eq!(complete("ls | A=1 to"), (9, vec!["tool ".into()]));
+        // Everything else is a path, quoted like the word.
+        assert_eq!(
+            complete("cat ~/my"),
+            (4, vec!["~/my\\ dir/".into(), "~/my\\ file.txt ".into()])
+        );
+        assert_eq!(
+            complete(&format!("cat \"{dir}/my f")),
+            (4, vec![format!("\"{dir}/my file.txt\" ")])
+        );
+        assert_eq!(complete("> ~/ot"), (2, vec!["~/other ".into()]));
+        assert_eq!(complete("cat ~/."), (4, vec!["~/.hidden ".into()]));
+        // Variables and jobs.
+        assert_eq!(
+            complete("echo \"${HO"),
+            (8, vec!["HOME}".into(), "HOST}".into()])
+        );
+        assert_eq!(complete("fg %"), (3, vec!["%1 ".into()]));
+    }
 }
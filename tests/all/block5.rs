@@ -15,3 +15,320 @@ fn test_pipes_evaluation() {
     let stdout_str = String::from_utf8(output.stdout).unwrap();
     assert_eq!(stdout_str.trim_start(), "6\n");
 }
+
+#[test]
+fn quoted_args_are_passed_verbatim() {
+    let output = ShellRunner::new()
+        .with_stdin("echo \"hello   world\" 'it''s'\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "hello   world its\n");
+}
+
+#[test]
+fn unpiped_output_stays_in_order() {
+    // The commands write straight to the stdout of the shell,
+    // so their output isn't collected and reordered by the shell.
+    let output = ShellRunner::new()
+        .with_stdin("echo a; sh -c 'echo b; echo c >&2; echo d' 2>&1; echo e\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "a\nb\nc\nd\ne\n");
+}
+
+#[test]
+fn pipes_stream_between_commands() {
+    let output = ShellRunner::new()
+        .with_stdin("yes | head -n 2\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "y\ny\n");
+}
+
+#[test]
+fn redirects_stdout_and_stderr_to_a_file() {
+    let path = std::env::temp_dir().join(format!("rush_redirect_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let output = ShellRunner::new()
+        .with_stdin(&format!(
+            "ls /nonexistent > {path} 2>&1; echo done >> {path}; wc -l < {path}\n"
+        ))
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+    std::fs::remove_file(path).unwrap();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str.trim_start(), "2\n");
+}
+
+#[test]
+fn heredoc_is_fed_to_stdin() {
+    let output = ShellRunner::new()
+        .with_stdin("cat <<EOF | wc -l\none\ntwo\nEOF\necho after\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str.trim_start(), "2\nafter\n");
+}
+
+#[test]
+fn backslash_continues_a_command_on_the_next_line() {
+    let output = ShellRunner::new()
+        .with_stdin("echo one \\\ntwo\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "one two\n");
+}
+
+#[test]
+fn variables_are_assigned_and_expanded() {
+    let output = ShellRunner::new()
+        .with_stdin("GREETING=hello; echo \"$GREETING world\" '$GREETING'\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "hello world $GREETING\n");
+}
+
+#[test]
+fn exit_status_is_tracked() {
+    let output = ShellRunner::new()
+        .with_stdin("false; echo $?; true; echo $?; false && echo no || echo $?\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "1\n0\n1\n");
+}
+
+#[test]
+fn parameter_operators_are_expanded() {
+    let output = ShellRunner::new()
+        .with_stdin("F=archive.tar.gz; echo ${F%%.*} ${#F} ${MISSING:-none} ${F/tar/zip}\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "archive 14 none archive.zip.gz\n");
+}
+
+#[test]
+fn command_output_is_substituted() {
+    let output = ShellRunner::new()
+        .with_stdin("echo \"[$(echo hello | tr a-z A-Z)]\" `echo a   b`\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "[HELLO] a b\n");
+}
+
+#[test]
+fn command_substitutions_dont_change_the_shell() {
+    let output = ShellRunner::new()
+        .with_stdin("cd /usr; x=$(exit 3); echo alive $?; echo $(cd /); pwd\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "alive 0\n\n/usr\n");
+}
+
+#[test]
+fn patterns_expand_to_sorted_paths() {
+    let output = ShellRunner::new()
+        .with_stdin("echo examples/block[3-1].rs examples/block[1-3].rs 'examples/*' none*.xyz\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout_str,
+        "examples/block[3-1].rs examples/block1.rs examples/block2.rs examples/block3.rs examples/* none*.xyz\n"
+    );
+}
+
+#[test]
+fn tilde_and_bare_cd_use_home() {
+    let output = ShellRunner::new()
+        .with_stdin("HOME=/tmp; cd; pwd; echo ~/src '~'\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "/tmp\n/tmp/src ~\n");
+}
+
+#[test]
+fn cd_tracks_the_previous_directory() {
+    let output = ShellRunner::new()
+        .with_stdin("cd /tmp; cd /; cd -; echo $OLDPWD $PWD; CDPATH=/; cd tmp\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "/tmp\n/ /tmp\n/tmp\n");
+}
+
+#[test]
+fn pushd_and_popd_manage_the_dir_stack() {
+    let output = ShellRunner::new()
+        .with_stdin("cd /; pushd /tmp; pushd /usr; pushd +2; popd; pwd\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout_str,
+        "/tmp /\n/usr /tmp /\n/ /usr /tmp\n/usr /tmp\n/usr\n"
+    );
+}
+
+#[test]
+fn braces_generate_words() {
+    let output = ShellRunner::new()
+        .with_stdin("echo config.{yaml,yaml.bak} logs/{api,worker} {08..10}\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout_str,
+        "config.yaml config.yaml.bak logs/api logs/worker 08 09 10\n"
+    );
+}
+
+#[test]
+fn arithmetic_is_computed() {
+    let output = ShellRunner::new()
+        .with_stdin(
+            "i=3; echo $(( i * (i + 1) / 2 )); (( i > 5 )) || echo small; let i+=10; echo $i\n",
+        )
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "6\nsmall\n13\n");
+}
+
+#[test]
+fn unquoted_expansions_are_split() {
+    let output = ShellRunner::new()
+        .with_stdin("args='-n hello'; echo $args; echo \"$args\"; IFS=:; p=a:b; printf '<%s>' $p\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "hello-n hello\n<a><b>");
+}
+
+#[test]
+fn assignments_before_a_command_only_apply_to_it() {
+    let output = ShellRunner::new()
+        .with_stdin("GREETING=hi sh -c 'echo $GREETING'; echo \"[$GREETING]\"\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "hi\n[]\n");
+}
+
+#[test]
+fn exported_variables_are_passed_to_commands() {
+    let output = ShellRunner::new()
+        .with_stdin("A=1; export B=2; sh -c 'echo \"[$A][$B]\"'; export A; unset B; sh -c 'echo \"[$A][$B]\"'\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "[][2]\n[1][]\n");
+}
+
+#[test]
+fn background_jobs_are_listed() {
+    let output = ShellRunner::new()
+        .with_stdin(
+            "sleep 2 > /dev/null &\njobs\ntrue &\nsleep 0.5\njobs\n[ -n \"$!\" ] && echo pid\n",
+        )
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    let running = "[1]+  Running                 sleep 2 > /dev/null &\n";
+    assert_eq!(stdout_str, format!("{running}{running}pid\n"));
+}
+
+#[test]
+fn background_chains_dont_change_the_shell() {
+    let output = ShellRunner::new()
+        .with_stdin(
+            "cd /usr\nexit 5 &\ncd /tmp &\nfalse && echo no || echo yes &\nsleep 0.5; pwd\n",
+        )
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout_str.lines().collect();
+    assert!(lines.contains(&"yes"), "{stdout_str}");
+    assert_eq!(lines.last(), Some(&"/usr"), "{stdout_str}");
+}
+
+#[test]
+fn jobs_can_be_continued_with_fg_and_bg() {
+    let output = ShellRunner::new()
+        .with_stdin("sleep 2 > /dev/null &\nkill -STOP $!\nsleep 0.2\njobs\nbg\nsh -c 'sleep 0.3; exit 3' &\nfg\necho $?\n")
+        .example("block5")
+        .kill_after(SHELL_TIMEOUT)
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout_str,
+        "[1]+  Stopped                 sleep 2 > /dev/null\n[1]+ sleep 2 > /dev/null &\nsh -c 'sleep 0.3; exit 3'\n3\n"
+    );
+}
+
+#[test]
+fn end_of_input_exits_with_the_last_status() {
+    // No `kill_after`: the shell has to exit on its own.
+    let output = ShellRunner::new()
+        .with_stdin("echo hi\nsh -c 'exit 3'\n")
+        .example("block5")
+        .run();
+
+    let stdout_str = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout_str, "hi\n");
+    assert_eq!(output.status.code(), Some(3));
+}